@@ -0,0 +1,188 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! PIV backends the server can perform operations with.
+
+mod hardware;
+mod management_key;
+mod pin;
+mod software;
+mod xeddsa;
+
+pub use hardware::YubiKeyBackend;
+pub use management_key::{Algorithm as ManagementKeyAlgorithm, ManagementKey};
+pub use software::{KeyAlgorithm as SoftwareKeyAlgorithm, SoftwareBackend};
+
+use std::fmt;
+
+use yubikey::{piv, PinPolicy, Serial, TouchPolicy};
+use zeroize::Zeroizing;
+
+use crate::{key_policy, slot};
+
+/// Whether a backend can currently perform operations.
+pub enum Status {
+    /// No device is connected.
+    NoDevice,
+    /// Operations can be performed, with a description of the device.
+    Ready(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::NoDevice => write!(f, "no_device"),
+            Status::Ready(description) => write!(f, "ready {description}"),
+        }
+    }
+}
+
+/// Description of a connected device.
+pub struct DeviceInfo {
+    pub serial: Serial,
+    /// Firmware version.
+    pub version: String,
+    /// Name of the reader the device is connected through.
+    pub reader: String,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.serial, self.version, self.reader)
+    }
+}
+
+/// Where the key in a slot comes from.
+#[derive(Clone, Copy)]
+pub enum KeyOrigin {
+    Generated,
+    Imported,
+}
+
+/// Description of a key held in a slot. Properties the device does not report are `None`.
+pub struct KeyInfo {
+    pub algorithm: String,
+    pub pin_policy: Option<PinPolicy>,
+    pub touch_policy: Option<TouchPolicy>,
+    pub origin: Option<KeyOrigin>,
+    /// X25519 public key, for X25519 and Ed25519 keys.
+    pub public_key: Option<[u8; 32]>,
+}
+
+/// What a slot holds.
+pub enum SlotKey {
+    Empty,
+    Key(KeyInfo),
+    /// The slot could not be read.
+    Unknown,
+}
+
+/// Description of a slot, with its key if any.
+pub struct SlotInfo {
+    pub slot: piv::SlotId,
+    pub key: SlotKey,
+}
+
+impl fmt::Display for SlotInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", slot::name(self.slot))?;
+        let key = match &self.key {
+            SlotKey::Empty => return write!(f, " empty"),
+            SlotKey::Key(key) => key,
+            SlotKey::Unknown => return write!(f, " unknown"),
+        };
+        write!(
+            f,
+            " {} pin={} touch={} origin={} public_key=",
+            key.algorithm,
+            key.pin_policy
+                .map_or("unknown", key_policy::pin_policy_name),
+            key.touch_policy
+                .map_or("unknown", key_policy::touch_policy_name),
+            match key.origin {
+                Some(KeyOrigin::Generated) => "generated",
+                Some(KeyOrigin::Imported) => "imported",
+                None => "unknown",
+            },
+        )?;
+        match &key.public_key {
+            // Formatted as Signal does, prefixed with 0x05 for Curve25519.
+            Some(public_key) => write!(f, "05{}", hex::encode(public_key)),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Operations the server performs on behalf of its clients.
+///
+/// A backend is owned by the worker thread, see [`crate::worker::Worker`].
+pub trait Backend: Send {
+    /// Selects the device the next operations are performed with, by serial, or the default
+    /// device when `None`.
+    fn select(&mut self, serial: Option<Serial>) -> anyhow::Result<()>;
+
+    /// Lists the connected devices.
+    fn list_devices(&mut self) -> anyhow::Result<Vec<DeviceInfo>>;
+
+    /// Describes every slot of the device, see [`crate::slot::SLOTS`].
+    fn list_slots(&mut self) -> anyhow::Result<Vec<SlotInfo>>;
+
+    /// Reports whether a device is available, attaching it if it was just connected.
+    fn status(&mut self) -> Status;
+
+    /// Whether the next operation with the key in `slot` waits for the user to touch the
+    /// device.
+    fn requires_touch(&mut self, slot: piv::SlotId) -> bool;
+
+    /// Computes the X25519 shared secret between the private key held in `slot` and
+    /// `their_key`.
+    fn calculate_agreement(
+        &mut self,
+        slot: piv::SlotId,
+        their_key: &[u8; 32],
+    ) -> anyhow::Result<Zeroizing<Vec<u8>>>;
+
+    /// Returns the X25519 public key matching the private key held in `slot`.
+    fn public_key(&mut self, slot: piv::SlotId) -> anyhow::Result<[u8; 32]>;
+
+    /// Verifies `pin`, unlocking the keys whose PIN policy requires it.
+    fn verify_pin(&mut self, pin: &[u8]) -> anyhow::Result<()>;
+
+    /// Replaces the PIN `current_pin` with `new_pin`.
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> anyhow::Result<()>;
+
+    /// Replaces the PUK `current_puk` with `new_puk`.
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> anyhow::Result<()>;
+
+    /// Sets the blocked PIN to `new_pin`, authenticating with `puk`.
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> anyhow::Result<()>;
+
+    /// Returns the number of PIN verification attempts left before the PIN is blocked.
+    fn pin_retries(&mut self) -> anyhow::Result<u8>;
+
+    /// Replaces the management key with a random one, returning it unless it is stored in the
+    /// PIN-protected data of the device.
+    fn rotate_management_key(&mut self) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>>;
+
+    /// Generates a new X25519 key pair in `slot`, replacing any key there, and returns its
+    /// public key.
+    fn generate_key(
+        &mut self,
+        slot: piv::SlotId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> anyhow::Result<[u8; 32]>;
+
+    /// Imports the X25519 `private_key` in `slot`, replacing any key there.
+    fn import_key(
+        &mut self,
+        slot: piv::SlotId,
+        private_key: &[u8; 32],
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> anyhow::Result<()>;
+
+    /// Signs `message` with the private key held in `slot`, returning a 64-byte signature
+    /// verifiable as an XEdDSA signature by the Montgomery form of its public key.
+    fn sign(&mut self, slot: piv::SlotId, message: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
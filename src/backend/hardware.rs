@@ -0,0 +1,841 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use log::{debug, info, warn};
+use yubikey::{
+    certificate::Certificate, piv, reader, MgmKey, PinPolicy, Serial, TouchPolicy, YubiKey,
+};
+use zeroize::Zeroizing;
+
+use super::{
+    management_key::Algorithm, pin::PinCache, Backend, DeviceInfo, KeyInfo, KeyOrigin,
+    ManagementKey, SlotInfo, SlotKey, Status,
+};
+use crate::error::{self, ErrorCode};
+
+/// Backend performing operations on YubiKeys.
+///
+/// Several YubiKeys can be connected, each command using the one selected with
+/// [`Backend::select`] or the default one. Devices are opened lazily: the server can start
+/// before they are inserted. They are also reopened when they are removed and reinserted, or
+/// when their PC/SC session is reset.
+pub struct YubiKeyBackend {
+    /// Devices opened so far, by serial. Lost devices are removed until they are reopened.
+    devices: HashMap<u32, YubiKey>,
+    /// Serial of the device used when none is selected. When not configured, it is the serial
+    /// of the first device opened, so that another one plugged in later is not picked up.
+    default_serial: Option<Serial>,
+    /// Serial of the device selected for the current command.
+    selected: Option<Serial>,
+    /// Key authenticating write operations, the factory default when `None`.
+    management_key: Option<ManagementKey>,
+    /// Keys replacing the management key of the devices they were rotated on, by serial.
+    rotated_keys: HashMap<u32, ManagementKey>,
+    /// Keys a device may have taken while being rotated, when it was lost before confirming
+    /// it, by serial. They are tried first and become its rotated key once they work.
+    unconfirmed_keys: HashMap<u32, ManagementKey>,
+    pins: PinCache,
+    /// PIN and touch policies of the keys, by device serial and slot, read from their
+    /// metadata.
+    policies: HashMap<(u32, u8), (PinPolicy, TouchPolicy)>,
+    /// When each device was last touched, for keys with the `cached` touch policy.
+    last_touches: HashMap<u32, Instant>,
+}
+
+impl YubiKeyBackend {
+    /// Backend using the YubiKey with the given `serial` by default, or the first one found
+    /// when `None`.
+    ///
+    /// The default device is opened right away if present, later otherwise.
+    pub fn new(
+        default_serial: Option<Serial>,
+        management_key: Option<ManagementKey>,
+        pin_helper: Option<PathBuf>,
+    ) -> Self {
+        let mut backend = Self {
+            devices: HashMap::new(),
+            default_serial,
+            selected: None,
+            management_key,
+            rotated_keys: HashMap::new(),
+            unconfirmed_keys: HashMap::new(),
+            pins: PinCache::new(pin_helper),
+            policies: HashMap::new(),
+            last_touches: HashMap::new(),
+        };
+        if let Err(err) = backend.open_device() {
+            warn!("{err:#}, waiting for it to be inserted");
+        }
+        backend
+    }
+
+    /// Opens the device to use for the current command if needed, returning its serial.
+    fn open_device(&mut self) -> anyhow::Result<Serial> {
+        let serial = self.selected.or(self.default_serial);
+        if let Some(serial) = serial {
+            if self.devices.contains_key(&serial.0) {
+                return Ok(serial);
+            }
+        }
+
+        let yubikey = match serial {
+            Some(serial) => YubiKey::open_by_serial(serial),
+            None => open_first_device(),
+        }
+        .map_err(|err| {
+            debug!("Failed to open device: {err}");
+            ErrorCode::DeviceAbsent.error(match serial {
+                Some(serial) => format!("Device unavailable: YubiKey {serial}"),
+                None => "Device unavailable".to_owned(),
+            })
+        })?;
+        let serial = yubikey.serial();
+        info!("Opened YubiKey {serial}");
+        if self.selected.is_none() {
+            self.default_serial = Some(serial);
+        }
+        self.devices.insert(serial.0, yubikey);
+        Ok(serial)
+    }
+
+    /// Runs `operation` on the device to use for the current command.
+    ///
+    /// If it fails because the device was removed or its session was reset, the device is
+    /// reopened and `operation` retried once. Operations writing to the device use
+    /// [`Self::write_device`] instead.
+    fn with_device<T>(
+        &mut self,
+        operation: impl Fn(&mut YubiKey) -> yubikey::Result<T>,
+    ) -> anyhow::Result<T> {
+        let serial = self.open_device()?;
+        match operation(self.opened(serial)) {
+            Err(err) if is_device_error(&err) => {
+                warn!("Lost YubiKey {serial}: {err}");
+                self.lose_device(serial);
+            }
+            result => return result.map_err(error::from_yubikey),
+        }
+        let serial = self.open_device()?;
+        operation(self.opened(serial)).map_err(|err| {
+            if is_device_error(&err) {
+                self.lose_device(serial);
+            }
+            error::from_yubikey(err)
+        })
+    }
+
+    /// Runs `operation`, which writes to the device, on the device to use for the current
+    /// command.
+    ///
+    /// Unlike [`Self::with_device`], `operation` is never retried: it may have taken effect
+    /// before the device was lost, and a reopened session is no longer authenticated. Failures
+    /// with [`ErrorCode::DeviceAbsent`] leave its outcome unknown.
+    fn write_device<T>(
+        &mut self,
+        operation: impl FnOnce(&mut YubiKey) -> yubikey::Result<T>,
+    ) -> anyhow::Result<T> {
+        let serial = self.open_device()?;
+        operation(self.opened(serial)).map_err(|err| {
+            if is_device_error(&err) {
+                warn!("Lost YubiKey {serial}: {err}");
+                self.lose_device(serial);
+            }
+            error::from_yubikey(err)
+        })
+    }
+
+    fn lose_device(&mut self, serial: Serial) {
+        self.devices.remove(&serial.0);
+        self.pins.reset(serial);
+    }
+
+    /// Runs `operation` with the key in `slot`, verifying the PIN first when its policy
+    /// requires it.
+    ///
+    /// PINs are verified once per session for keys with the `once` policy. Keys with the
+    /// `always` policy need the PIN right before every operation: it is asked from the PIN
+    /// helper each time, or must have just been verified by the client.
+    fn with_key<T>(
+        &mut self,
+        slot: piv::SlotId,
+        operation: impl Fn(&mut YubiKey) -> yubikey::Result<T>,
+    ) -> anyhow::Result<T> {
+        let serial = self.open_device()?;
+        let (pin_policy, touch_policy) = self.key_policies(serial, slot)?;
+        let pin = match pin_policy {
+            PinPolicy::Never => None,
+            PinPolicy::Always => self.pins.ask(serial, slot)?,
+            _ => self.session_pin(serial, slot)?,
+        };
+        // Whether the PIN cannot be why the operation is refused: it is not needed, was just
+        // verified, or the session is believed to be verified.
+        let pin_ruled_out = match pin_policy {
+            PinPolicy::Never => true,
+            _ if pin.is_some() => true,
+            PinPolicy::Always => false,
+            _ => self.pins.is_verified(serial),
+        };
+        let touch = self.touch_required(serial, touch_policy);
+        let started = Instant::now();
+        let result = self
+            .with_device(|yubikey| {
+                if let Some(pin) = &pin {
+                    yubikey.verify_pin(pin)?;
+                }
+                operation(yubikey)
+            })
+            .map_err(|err| {
+                // The device reports a missing touch as a generic failure, or as an unsatisfied
+                // security status, once it gave up waiting.
+                let unexplained = match ErrorCode::find(&err) {
+                    None | Some(ErrorCode::Internal) => true,
+                    Some(ErrorCode::PinRequired) => pin_ruled_out,
+                    Some(_) => false,
+                };
+                if touch && unexplained && started.elapsed() >= TOUCH_TIMEOUT_THRESHOLD {
+                    ErrorCode::TouchTimeout
+                        .error(format!("Touch not detected on YubiKey {serial} in time"))
+                } else {
+                    err
+                }
+            });
+        self.record_pin_result(serial, pin.is_some(), &result);
+        if matches!(touch_policy, TouchPolicy::Always | TouchPolicy::Cached) && result.is_ok() {
+            self.last_touches.insert(serial.0, Instant::now());
+        }
+        result
+    }
+
+    /// Returns the PIN to verify before an operation needing a verified session, `None` if the
+    /// session of `serial` already is.
+    fn session_pin(
+        &self,
+        serial: Serial,
+        slot: piv::SlotId,
+    ) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>> {
+        if self.pins.is_verified(serial) {
+            return Ok(None);
+        }
+        self.pins.get(serial, slot).map(Some)
+    }
+
+    /// Updates the PIN verification state of `serial` after an operation, which verified the
+    /// PIN first if `verified_pin`.
+    fn record_pin_result<T>(
+        &mut self,
+        serial: Serial,
+        verified_pin: bool,
+        result: &anyhow::Result<T>,
+    ) {
+        match result {
+            Ok(_) if verified_pin => self.pins.set_verified(serial),
+            // The device may also have dropped the verification on its own, e.g. when another
+            // PC/SC client reset it: it is verified again next time.
+            Err(err) if is_pin_error(err) || needs_pin(err) => self.pins.reset(serial),
+            _ => {}
+        }
+    }
+
+    /// Returns the PIN and touch policies of the key in `slot`, `once` and `never` when they
+    /// cannot be read.
+    ///
+    /// Fails with [`ErrorCode::SlotEmpty`] when the slot holds no key, rather than asking for
+    /// a PIN it does not need.
+    fn key_policies(
+        &mut self,
+        serial: Serial,
+        slot: piv::SlotId,
+    ) -> anyhow::Result<(PinPolicy, TouchPolicy)> {
+        if let Some(policies) = self.policies.get(&(serial.0, u8::from(slot))) {
+            return Ok(*policies);
+        }
+        match self.with_device(|yubikey| piv::metadata(yubikey, slot)) {
+            Ok(metadata) => {
+                let policies = metadata
+                    .policy
+                    .unwrap_or((PinPolicy::Once, TouchPolicy::Never));
+                self.policies.insert((serial.0, u8::from(slot)), policies);
+                Ok(policies)
+            }
+            Err(err) if ErrorCode::find(&err) == Some(ErrorCode::SlotEmpty) => {
+                Err(err.context(format!("No key in slot {}", crate::slot::name(slot))))
+            }
+            Err(err) => {
+                // Firmware older than 5.3 has no metadata.
+                debug!(
+                    "Failed to read metadata of slot {}: {err:#}",
+                    crate::slot::name(slot)
+                );
+                Ok((PinPolicy::Once, TouchPolicy::Never))
+            }
+        }
+    }
+
+    /// Whether the next operation with a key with `touch_policy` waits for a touch.
+    fn touch_required(&self, serial: Serial, touch_policy: TouchPolicy) -> bool {
+        match touch_policy {
+            TouchPolicy::Always => true,
+            TouchPolicy::Cached => self
+                .last_touches
+                .get(&serial.0)
+                .is_none_or(|touched| touched.elapsed() >= TOUCH_CACHE_DURATION),
+            _ => false,
+        }
+    }
+
+    /// Authenticates with the management key, allowing write operations until the device is
+    /// reset.
+    fn authenticate(&mut self) -> anyhow::Result<()> {
+        let serial = self.open_device()?;
+        let version = self.opened(serial).version();
+        if let Some(unconfirmed_key) = self.unconfirmed_keys.remove(&serial.0) {
+            let mgm_key = unconfirmed_key
+                .to_mgm_key(version)?
+                .expect("generated keys are manual");
+            match self.with_device(|yubikey| yubikey.authenticate(mgm_key.clone())) {
+                Ok(()) => {
+                    warn!("YubiKey {serial} took the management key it was lost rotating to");
+                    self.rotated_keys.insert(serial.0, unconfirmed_key);
+                    return Ok(());
+                }
+                Err(err) if ErrorCode::find(&err) == Some(ErrorCode::PinRequired) => {
+                    info!("YubiKey {serial} kept its management key while it was lost rotating it");
+                }
+                Err(err) => {
+                    self.unconfirmed_keys.insert(serial.0, unconfirmed_key);
+                    return Err(err);
+                }
+            }
+        }
+        let management_key = match self.management_key_of(serial) {
+            Some(management_key) => management_key.to_mgm_key(version)?,
+            None => {
+                warn!("Authenticating with the default management key");
+                ManagementKey::factory_default().to_mgm_key(version)?
+            }
+        };
+        let management_key = match management_key {
+            Some(management_key) => management_key,
+            None => self.read_protected_key(serial)?,
+        };
+        self.with_device(|yubikey| yubikey.authenticate(management_key.clone()))
+            .map_err(|err| match ErrorCode::find(&err) {
+                Some(ErrorCode::PinRequired) => {
+                    ErrorCode::AccessDenied.error("Management key authentication failed")
+                }
+                _ => err,
+            })
+    }
+
+    /// Management key of `serial`: the one it was rotated to, or the configured one.
+    fn management_key_of(&self, serial: Serial) -> Option<&ManagementKey> {
+        self.rotated_keys
+            .get(&serial.0)
+            .or(self.management_key.as_ref())
+    }
+
+    /// Reads the management key stored in the PIN-protected data of `serial`.
+    fn read_protected_key(&mut self, serial: Serial) -> anyhow::Result<MgmKey> {
+        let pin = self.session_pin(serial, piv::SlotId::Management)?;
+        let result = self.with_device(|yubikey| {
+            if let Some(pin) = &pin {
+                yubikey.verify_pin(pin)?;
+            }
+            MgmKey::get_protected(yubikey)
+        });
+        self.record_pin_result(serial, pin.is_some(), &result);
+        result.context("Failed to read the PIN-protected management key")
+    }
+
+    /// Describes the key in `slot`, `None` if there is none.
+    fn key_info(&mut self, slot: piv::SlotId) -> anyhow::Result<Option<KeyInfo>> {
+        // Firmware older than 5.3 has no metadata: only a certificate tells there is a key.
+        let metadata = self
+            .with_device(|yubikey| piv::metadata(yubikey, slot))
+            .ok();
+        let public_key =
+            match self.with_device(|yubikey| read_public_key(yubikey, slot, metadata.as_ref())) {
+                Ok(public_key) => Some(public_key),
+                Err(err) if ErrorCode::find(&err) == Some(ErrorCode::SlotEmpty) => None,
+                Err(err) => return Err(err),
+            };
+        if metadata.is_none() && public_key.is_none() {
+            return Ok(None);
+        }
+
+        let policy = metadata.as_ref().and_then(|metadata| metadata.policy);
+        Ok(Some(KeyInfo {
+            algorithm: public_key
+                .as_ref()
+                .map_or("unknown", |(algorithm, _)| algorithm_name(algorithm))
+                .to_owned(),
+            pin_policy: policy.map(|(pin_policy, _)| pin_policy),
+            touch_policy: policy.map(|(_, touch_policy)| touch_policy),
+            origin: metadata
+                .and_then(|metadata| metadata.origin)
+                .map(|origin| match origin {
+                    piv::Origin::Generated => KeyOrigin::Generated,
+                    piv::Origin::Imported => KeyOrigin::Imported,
+                }),
+            public_key: public_key
+                .and_then(|(algorithm, public_key)| x25519_public_key(&algorithm, public_key).ok()),
+        }))
+    }
+
+    /// Forgets the cached policies of the key in `slot`, which is being replaced.
+    fn forget_policies(&mut self, slot: piv::SlotId) {
+        if let Some(serial) = self.selected.or(self.default_serial) {
+            self.policies.remove(&(serial.0, u8::from(slot)));
+        }
+    }
+
+    fn opened(&mut self, serial: Serial) -> &mut YubiKey {
+        self.devices
+            .get_mut(&serial.0)
+            .expect("device was just opened")
+    }
+}
+
+/// Opens the YubiKey of the first reader that has one, in the order `list_devices` lists them.
+///
+/// Unlike [`YubiKey::open`], this does not fail when several YubiKeys are connected.
+fn open_first_device() -> yubikey::Result<YubiKey> {
+    let mut context = reader::Context::open()?;
+    let mut last_error = yubikey::Error::NotFound;
+    for reader in context.iter()? {
+        match reader.open() {
+            Ok(yubikey) => return Ok(yubikey),
+            Err(err) => {
+                debug!("Skipping reader {:?}: {err}", reader.name());
+                last_error = err;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Object identifiers of the key algorithms, as found in public key infos.
+const X25519_OID: &str = "1.3.101.110";
+const ED25519_OID: &str = "1.3.101.112";
+const EC_OID: &str = "1.2.840.10045.2.1";
+const RSA_OID: &str = "1.2.840.113549.1.1.1";
+
+/// Name of the key algorithm with object identifier `oid`.
+fn algorithm_name(oid: &str) -> &str {
+    match oid {
+        X25519_OID => "x25519",
+        ED25519_OID => "ed25519",
+        EC_OID => "ecc",
+        RSA_OID => "rsa",
+        other => other,
+    }
+}
+
+/// Converts a public key read from a slot to an X25519 public key.
+///
+/// Ed25519 keys, used for signing, are converted to their X25519 form.
+fn x25519_public_key(oid: &str, public_key: Vec<u8>) -> anyhow::Result<[u8; 32]> {
+    let public_key: [u8; 32] = match oid {
+        X25519_OID | ED25519_OID => public_key.try_into().map_err(|key: Vec<u8>| {
+            anyhow!(
+                "Invalid length for public key. Expected '32', got: {}",
+                key.len()
+            )
+        })?,
+        other => {
+            return Err(ErrorCode::Unsupported.error(format!(
+                "Unsupported key algorithm: {}",
+                algorithm_name(other)
+            )))
+        }
+    };
+    match oid {
+        ED25519_OID => CompressedEdwardsY(public_key)
+            .decompress()
+            .map(|point| point.to_montgomery().to_bytes())
+            .ok_or_else(|| anyhow!("Invalid Ed25519 public key")),
+        _ => Ok(public_key),
+    }
+}
+
+/// Reads the public key of `slot`, returning the object identifier of its algorithm and its
+/// raw bytes.
+///
+/// The key is read from the slot `metadata`, or from the slot certificate on firmware older
+/// than 5.3, which has no metadata.
+fn read_public_key(
+    yubikey: &mut YubiKey,
+    slot: piv::SlotId,
+    metadata: Option<&piv::SlotMetadata>,
+) -> yubikey::Result<(String, Vec<u8>)> {
+    let public_key = match metadata.and_then(|metadata| metadata.public.clone()) {
+        Some(public_key) => public_key,
+        None => {
+            Certificate::read(yubikey, slot)?
+                .cert
+                .tbs_certificate
+                .subject_public_key_info
+        }
+    };
+    Ok((
+        public_key.algorithm.oid.to_string(),
+        public_key.subject_public_key.raw_bytes().to_vec(),
+    ))
+}
+
+/// How long a touch is remembered for keys with the `cached` touch policy.
+const TOUCH_CACHE_DURATION: Duration = Duration::from_secs(15);
+
+/// Operations waiting for a touch failing after this long are assumed to have timed out. The
+/// device gives up after about 15 seconds.
+const TOUCH_TIMEOUT_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// First firmware version, as `(major, minor)`, supporting Ed25519 keys.
+const ED25519_MIN_VERSION: (u8, u8) = (5, 7);
+
+/// Whether `err` means the PIN was rejected, in which case it must not be tried again.
+fn is_pin_error(err: &anyhow::Error) -> bool {
+    matches!(
+        ErrorCode::find(err),
+        Some(ErrorCode::WrongPin | ErrorCode::PinBlocked)
+    )
+}
+
+/// Whether `err` means the PIN must be verified for the operation to succeed.
+fn needs_pin(err: &anyhow::Error) -> bool {
+    ErrorCode::find(err) == Some(ErrorCode::PinRequired)
+}
+
+/// Whether `err` means the device is gone or must be reopened.
+fn is_device_error(err: &yubikey::Error) -> bool {
+    matches!(err, yubikey::Error::PcscError { .. })
+}
+
+impl Backend for YubiKeyBackend {
+    fn select(&mut self, serial: Option<Serial>) -> anyhow::Result<()> {
+        self.selected = serial;
+        Ok(())
+    }
+
+    fn list_devices(&mut self) -> anyhow::Result<Vec<DeviceInfo>> {
+        let mut context = reader::Context::open()
+            .map_err(|err| anyhow!("{err}"))
+            .context("Failed to open PC/SC context")?;
+        let readers = context
+            .iter()
+            .map_err(|err| anyhow!("{err}"))
+            .context("Failed to list readers")?;
+
+        let mut devices = Vec::new();
+        for reader in readers {
+            let reader_name = reader.name().into_owned();
+            match reader.open() {
+                Ok(yubikey) => {
+                    // Opening the device resets its session, losing the verification of its
+                    // PIN.
+                    self.pins.reset(yubikey.serial());
+                    devices.push(DeviceInfo {
+                        serial: yubikey.serial(),
+                        version: yubikey.version().to_string(),
+                        reader: reader_name,
+                    });
+                }
+                Err(err) => debug!("Skipping reader {reader_name:?}: {err}"),
+            }
+        }
+        Ok(devices)
+    }
+
+    fn list_slots(&mut self) -> anyhow::Result<Vec<SlotInfo>> {
+        let mut slots = Vec::new();
+        for (_, slot) in &crate::slot::SLOTS {
+            let key = match self.key_info(*slot) {
+                Ok(Some(key)) => SlotKey::Key(key),
+                Ok(None) => SlotKey::Empty,
+                // A device that is gone fails every slot: report it rather than a list of
+                // unknown slots.
+                Err(err) if ErrorCode::find(&err) == Some(ErrorCode::DeviceAbsent) => {
+                    return Err(err)
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to describe slot {}: {err:#}",
+                        crate::slot::name(*slot)
+                    );
+                    SlotKey::Unknown
+                }
+            };
+            slots.push(SlotInfo { slot: *slot, key });
+        }
+        Ok(slots)
+    }
+
+    fn status(&mut self) -> Status {
+        // Beginning a transaction fails when the device is gone, making this a cheap probe.
+        let probe = self.with_device(|yubikey| {
+            yubikey.begin_transaction()?;
+            Ok(yubikey.serial())
+        });
+        match probe {
+            Ok(serial) => {
+                let yubikey = self.opened(serial);
+                Status::Ready(format!(
+                    "serial={} version={}",
+                    yubikey.serial(),
+                    yubikey.version()
+                ))
+            }
+            Err(err) => {
+                debug!("No device: {err:#}");
+                Status::NoDevice
+            }
+        }
+    }
+
+    fn requires_touch(&mut self, slot: piv::SlotId) -> bool {
+        match self.open_device() {
+            Ok(serial) => {
+                let Ok((_, touch_policy)) = self.key_policies(serial, slot) else {
+                    return false;
+                };
+                self.touch_required(serial, touch_policy)
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn calculate_agreement(
+        &mut self,
+        slot: piv::SlotId,
+        their_key: &[u8; 32],
+    ) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let result = self.with_key(slot, |yubikey| {
+            let transaction = yubikey.begin_transaction()?;
+            piv::decrypt_data_with_transaction(
+                &transaction,
+                their_key,
+                piv::AlgorithmId::X25519,
+                slot,
+            )
+        });
+        if let Err(err) = &result {
+            // Ed25519 keys, used for signing, cannot calculate agreements: report them the way
+            // `sign` reports X25519 keys rather than as a device failure.
+            if matches!(ErrorCode::find(err), None | Some(ErrorCode::Internal)) {
+                if let Ok((algorithm, _)) = self.with_device(|yubikey| {
+                    let metadata = piv::metadata(yubikey, slot).ok();
+                    read_public_key(yubikey, slot, metadata.as_ref())
+                }) {
+                    if algorithm != X25519_OID {
+                        return Err(ErrorCode::Unsupported.error(format!(
+                            "Key agreement requires an X25519 key, not {} in slot {}",
+                            algorithm_name(&algorithm),
+                            crate::slot::name(slot)
+                        )));
+                    }
+                }
+            }
+        }
+        result.context("Yubikey failed to calculate agreement")
+    }
+
+    fn public_key(&mut self, slot: piv::SlotId) -> anyhow::Result<[u8; 32]> {
+        let (algorithm, public_key) = self
+            .with_device(|yubikey| {
+                let metadata = piv::metadata(yubikey, slot).ok();
+                read_public_key(yubikey, slot, metadata.as_ref())
+            })
+            .context("Yubikey failed to read public key")?;
+        x25519_public_key(&algorithm, public_key)
+            .with_context(|| format!("Unusable key in slot {}", crate::slot::name(slot)))
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> anyhow::Result<()> {
+        let serial = self.open_device()?;
+        let result = self.with_device(|yubikey| yubikey.verify_pin(pin));
+        self.record_pin_result(serial, true, &result);
+        result?;
+        info!("Verified PIN of YubiKey {serial}");
+        Ok(())
+    }
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> anyhow::Result<()> {
+        let serial = self.open_device()?;
+        let result = self.write_device(|yubikey| yubikey.change_pin(current_pin, new_pin));
+        self.record_pin_result(serial, false, &result);
+        result?;
+        info!("Changed PIN of YubiKey {serial}");
+        Ok(())
+    }
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> anyhow::Result<()> {
+        let serial = self.open_device()?;
+        self.write_device(|yubikey| yubikey.change_puk(current_puk, new_puk))?;
+        info!("Changed PUK of YubiKey {serial}");
+        Ok(())
+    }
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> anyhow::Result<()> {
+        let serial = self.open_device()?;
+        self.write_device(|yubikey| yubikey.unblock_pin(puk, new_pin))?;
+        info!("Unblocked PIN of YubiKey {serial}");
+        Ok(())
+    }
+
+    fn pin_retries(&mut self) -> anyhow::Result<u8> {
+        let serial = self.open_device()?;
+        let retries = self.with_device(|yubikey| yubikey.get_pin_retries());
+        // Reading the counter selects the PIV application again, losing the verification of
+        // the PIN.
+        self.pins.reset(serial);
+        retries
+    }
+
+    fn rotate_management_key(&mut self) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>> {
+        self.authenticate()?;
+        let serial = self.open_device()?;
+        let version = self.opened(serial).version();
+        let new_key = ManagementKey::generate(Algorithm::strongest_for(version));
+        let mgm_key = new_key
+            .to_mgm_key(version)?
+            .expect("generated keys are manual");
+
+        if matches!(
+            self.management_key_of(serial),
+            Some(ManagementKey::PinProtected)
+        ) {
+            let pin = self.session_pin(serial, piv::SlotId::Management)?;
+            let result = self.write_device(|yubikey| {
+                if let Some(pin) = &pin {
+                    yubikey.verify_pin(pin)?;
+                }
+                mgm_key.set_protected(yubikey)
+            });
+            self.record_pin_result(serial, pin.is_some(), &result);
+            result.context("Failed to store the new management key")?;
+            info!("Rotated PIN-protected management key of YubiKey {serial}");
+            return Ok(None);
+        }
+
+        if let Err(err) = self.write_device(|yubikey| mgm_key.set_manual(yubikey, false)) {
+            if ErrorCode::find(&err) == Some(ErrorCode::DeviceAbsent) {
+                // The device may have taken the new key before it was lost: keep it, to be
+                // tried once the device is back.
+                self.unconfirmed_keys.insert(serial.0, new_key);
+                return Err(err.context(
+                    "Lost the YubiKey while storing the new management key, rotate it again once \
+                     it is back",
+                ));
+            }
+            return Err(err).context("Failed to store the new management key");
+        }
+        info!("Rotated management key of YubiKey {serial}");
+        let bytes = new_key.bytes();
+        self.rotated_keys.insert(serial.0, new_key);
+        Ok(bytes)
+    }
+
+    fn generate_key(
+        &mut self,
+        slot: piv::SlotId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> anyhow::Result<[u8; 32]> {
+        self.authenticate()?;
+        self.forget_policies(slot);
+        let public_key = self
+            .write_device(|yubikey| {
+                piv::generate(
+                    yubikey,
+                    slot,
+                    piv::AlgorithmId::X25519,
+                    pin_policy,
+                    touch_policy,
+                )
+            })
+            .context("Yubikey failed to generate key")?;
+        info!("Generated key in slot {}", crate::slot::name(slot));
+        // X25519 keys cannot sign, so no self-signed certificate is written: the public key is
+        // found in the slot metadata instead.
+        public_key
+            .subject_public_key
+            .raw_bytes()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid length for generated public key"))
+    }
+
+    fn import_key(
+        &mut self,
+        slot: piv::SlotId,
+        private_key: &[u8; 32],
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> anyhow::Result<()> {
+        self.authenticate()?;
+        self.forget_policies(slot);
+        self.write_device(|yubikey| {
+            piv::import_ecc_key(
+                yubikey,
+                slot,
+                piv::AlgorithmId::X25519,
+                private_key,
+                touch_policy,
+                pin_policy,
+            )
+        })
+        .context("Yubikey failed to import key")
+    }
+
+    fn sign(&mut self, slot: piv::SlotId, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // PIV keys support a single algorithm, and XEdDSA needs the private scalar: signing
+        // relies on an Ed25519 key in the slot instead. XEdDSA verifiers derive the Edwards
+        // public key from the X25519 one, taking its sign bit from the top bit of the signature,
+        // which is always 0 in Ed25519 signatures.
+        let serial = self.open_device()?;
+        let version = self.opened(serial).version();
+        if (version.major, version.minor) < ED25519_MIN_VERSION {
+            return Err(ErrorCode::Unsupported.error(format!(
+                "Signing requires Ed25519 support, YubiKey {serial} has firmware {version}"
+            )));
+        }
+        let (algorithm, public_key) = self
+            .with_device(|yubikey| {
+                let metadata = piv::metadata(yubikey, slot).ok();
+                read_public_key(yubikey, slot, metadata.as_ref())
+            })
+            .context("Yubikey failed to read public key")?;
+        let sign_bit = match algorithm.as_str() {
+            ED25519_OID if public_key.len() == 32 => public_key[31] & 0x80,
+            ED25519_OID => bail!(
+                "Invalid Ed25519 public key in slot {}",
+                crate::slot::name(slot)
+            ),
+            other => {
+                return Err(ErrorCode::Unsupported.error(format!(
+                    "Signing requires an Ed25519 key, not {} in slot {}",
+                    algorithm_name(other),
+                    crate::slot::name(slot)
+                )))
+            }
+        };
+        let mut signature = self
+            .with_key(slot, |yubikey| {
+                piv::sign_data(yubikey, message, piv::AlgorithmId::Ed25519, slot)
+                    .map(|signature| signature.to_vec())
+            })
+            .context("Yubikey failed to sign")?;
+        if signature.len() != 64 {
+            bail!("Invalid Ed25519 signature length: {}", signature.len());
+        }
+        signature[63] |= sign_bit;
+        Ok(signature)
+    }
+}
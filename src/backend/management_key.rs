@@ -0,0 +1,165 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Context};
+use rand_core::{OsRng, RngCore};
+use yubikey::{MgmAlgorithmId, MgmKey};
+use zeroize::Zeroizing;
+
+/// Value of the `management_key` setting selecting a key stored on the device.
+const PIN_PROTECTED: &str = "pin-protected";
+
+/// Factory default management key, shared by every YubiKey.
+const DEFAULT_KEY: [u8; 24] = [
+    1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8,
+];
+
+/// Algorithms of management keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    TripleDes,
+    Aes128,
+    Aes192,
+    Aes256,
+}
+
+impl Algorithm {
+    /// Parses an algorithm as named in the configuration.
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "3des" => Ok(Algorithm::TripleDes),
+            "aes128" => Ok(Algorithm::Aes128),
+            "aes192" => Ok(Algorithm::Aes192),
+            "aes256" => Ok(Algorithm::Aes256),
+            _ => bail!(
+                "Invalid management key algorithm: {name}. Valid algorithms are 3des, aes128, \
+                 aes192 and aes256"
+            ),
+        }
+    }
+
+    /// Algorithm of the factory default key, AES-192 from firmware 5.7 and 3DES before.
+    pub fn default_for(version: yubikey::Version) -> Self {
+        if (version.major, version.minor) >= (5, 7) {
+            Algorithm::Aes192
+        } else {
+            Algorithm::TripleDes
+        }
+    }
+
+    /// Strongest algorithm supported by firmware `version`, AES-256 from firmware 5.4 and 3DES
+    /// before.
+    pub fn strongest_for(version: yubikey::Version) -> Self {
+        if (version.major, version.minor) >= (5, 4) {
+            Algorithm::Aes256
+        } else {
+            Algorithm::TripleDes
+        }
+    }
+
+    fn key_len(self) -> usize {
+        match self {
+            Algorithm::Aes128 => 16,
+            Algorithm::TripleDes | Algorithm::Aes192 => 24,
+            Algorithm::Aes256 => 32,
+        }
+    }
+
+    fn id(self) -> MgmAlgorithmId {
+        match self {
+            Algorithm::TripleDes => MgmAlgorithmId::ThreeDes,
+            Algorithm::Aes128 => MgmAlgorithmId::Aes128,
+            Algorithm::Aes192 => MgmAlgorithmId::Aes192,
+            Algorithm::Aes256 => MgmAlgorithmId::Aes256,
+        }
+    }
+}
+
+/// Management key authenticating operations that write to a YubiKey.
+///
+/// Never displayed, so that it stays out of the logs and of `--check-config`.
+#[derive(Clone)]
+pub enum ManagementKey {
+    /// Key given in the configuration. Its algorithm is inferred from its length, or for
+    /// 24-byte keys from the firmware version, when not configured.
+    Manual {
+        key: Zeroizing<Vec<u8>>,
+        algorithm: Option<Algorithm>,
+    },
+    /// Key stored in the PIN-protected data of the device, readable once the PIN is verified.
+    PinProtected,
+}
+
+impl ManagementKey {
+    /// Parses the `management_key` setting: a hex-encoded key or `pin-protected`.
+    pub fn parse(value: &str, algorithm: Option<Algorithm>) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if value == PIN_PROTECTED {
+            return Ok(ManagementKey::PinProtected);
+        }
+        let key = Zeroizing::new(hex::decode(value).context("Invalid management key")?);
+        let valid = match algorithm {
+            Some(algorithm) => key.len() == algorithm.key_len(),
+            None => matches!(key.len(), 16 | 24 | 32),
+        };
+        if !valid {
+            bail!("Invalid length for management key: {} bytes", key.len());
+        }
+        Ok(ManagementKey::Manual { key, algorithm })
+    }
+
+    /// Factory default key.
+    pub fn factory_default() -> Self {
+        ManagementKey::Manual {
+            key: Zeroizing::new(DEFAULT_KEY.to_vec()),
+            algorithm: None,
+        }
+    }
+
+    /// Generates a random key for `algorithm`.
+    pub fn generate(algorithm: Algorithm) -> Self {
+        let mut key = Zeroizing::new(vec![0u8; algorithm.key_len()]);
+        OsRng.fill_bytes(&mut key);
+        ManagementKey::Manual {
+            key,
+            algorithm: Some(algorithm),
+        }
+    }
+
+    /// Builds the key for a device running firmware `version`, or `None` for a PIN-protected
+    /// key, which must be read from the device.
+    pub(super) fn to_mgm_key(&self, version: yubikey::Version) -> anyhow::Result<Option<MgmKey>> {
+        let ManagementKey::Manual { key, algorithm } = self else {
+            return Ok(None);
+        };
+        let algorithm = algorithm.unwrap_or(match key.len() {
+            16 => Algorithm::Aes128,
+            32 => Algorithm::Aes256,
+            _ => Algorithm::default_for(version),
+        });
+        MgmKey::from_bytes(key, Some(algorithm.id()))
+            .map(Some)
+            .map_err(|err| anyhow!("Invalid management key: {err}"))
+    }
+
+    /// Bytes of a manual key, to hand it to administrators after a rotation.
+    pub(super) fn bytes(&self) -> Option<Zeroizing<Vec<u8>>> {
+        match self {
+            ManagementKey::Manual { key, .. } => Some(key.clone()),
+            ManagementKey::PinProtected => None,
+        }
+    }
+}
+
+impl fmt::Debug for ManagementKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManagementKey::Manual { algorithm, .. } => {
+                write!(f, "ManagementKey::Manual(<redacted>, {algorithm:?})")
+            }
+            ManagementKey::PinProtected => f.write_str("ManagementKey::PinProtected"),
+        }
+    }
+}
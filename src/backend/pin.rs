@@ -0,0 +1,135 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::{
+    collections::HashSet,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, warn};
+use yubikey::{piv, Serial};
+use zeroize::Zeroizing;
+
+use crate::{error::ErrorCode, slot};
+
+/// Whether the PIN of each device is verified in its current session.
+///
+/// PINs themselves are never kept: once a session ends, or for keys whose policy requires a
+/// verification before each operation, they are asked for again.
+pub struct PinCache {
+    /// Devices whose current session has a verified PIN.
+    verified: HashSet<u32>,
+    /// Program asked for the PIN when it is needed.
+    helper: Option<PathBuf>,
+}
+
+impl PinCache {
+    pub fn new(helper: Option<PathBuf>) -> Self {
+        Self {
+            verified: HashSet::new(),
+            helper,
+        }
+    }
+
+    /// Records that the PIN was just verified on the device `serial`.
+    pub fn set_verified(&mut self, serial: Serial) {
+        self.verified.insert(serial.0);
+    }
+
+    /// Records that the session of `serial` ended, or that its PIN was rejected, so the PIN
+    /// must be verified again.
+    pub fn reset(&mut self, serial: Serial) {
+        self.verified.remove(&serial.0);
+    }
+
+    pub fn is_verified(&self, serial: Serial) -> bool {
+        self.verified.contains(&serial.0)
+    }
+
+    /// Asks the helper for the PIN of `serial`, returning `None` if there is no helper.
+    pub fn ask(
+        &self,
+        serial: Serial,
+        slot: piv::SlotId,
+    ) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>> {
+        self.helper
+            .as_deref()
+            .map(|helper| run_helper(helper, serial, slot))
+            .transpose()
+    }
+
+    /// Returns the PIN of `serial` from the helper, failing if there is none.
+    pub fn get(&self, serial: Serial, slot: piv::SlotId) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        self.ask(serial, slot)?.ok_or_else(|| {
+            ErrorCode::PinRequired.error(format!(
+                "PIN required for slot {} of YubiKey {serial}",
+                slot::name(slot)
+            ))
+        })
+    }
+}
+
+/// How long the PIN helper has to print the PIN, giving the user time to type it.
+const HELPER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the PIN helper is checked for completion.
+const HELPER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs the PIN helper as `<helper> <serial> <slot>`, reading the PIN from the first line of
+/// its output.
+///
+/// The helper is killed if it does not exit within [`HELPER_TIMEOUT`].
+fn run_helper(
+    helper: &Path,
+    serial: Serial,
+    slot: piv::SlotId,
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    debug!("Asking {helper:?} for the PIN of YubiKey {serial}");
+    let mut child = Command::new(helper)
+        .arg(serial.to_string())
+        .arg(slot::name(slot))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to run PIN helper {helper:?}"))?;
+
+    // Other commands wait for the helper, which must not block them forever.
+    let deadline = Instant::now() + HELPER_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("Failed to wait for PIN helper {helper:?}"))?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            warn!("PIN helper {helper:?} did not answer in time, killing it");
+            // It may have exited in the meantime, leaving nothing to kill.
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ErrorCode::PinRequired.error(format!("PIN helper {helper:?} timed out")));
+        }
+        thread::sleep(HELPER_POLL_INTERVAL);
+    };
+
+    let mut stdout = Zeroizing::new(Vec::new());
+    if let Some(mut pipe) = child.stdout.take() {
+        pipe.read_to_end(&mut stdout)
+            .with_context(|| format!("Failed to read the output of PIN helper {helper:?}"))?;
+    }
+    if !status.success() {
+        return Err(ErrorCode::PinRequired.error(format!("PIN helper {helper:?} failed: {status}")));
+    }
+    let pin = stdout.split(|byte| *byte == b'\n').next().unwrap_or(&[]);
+    let pin = pin.strip_suffix(b"\r").unwrap_or(pin);
+    if pin.is_empty() {
+        return Err(ErrorCode::PinRequired.error("PIN helper returned no PIN"));
+    }
+    Ok(Zeroizing::new(pin.to_vec()))
+}
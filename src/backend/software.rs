@@ -0,0 +1,288 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info};
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+use yubikey::{piv, PinPolicy, Serial, TouchPolicy};
+use zeroize::Zeroizing;
+
+use super::{xeddsa, Backend, DeviceInfo, KeyInfo, KeyOrigin, SlotInfo, SlotKey, Status};
+use crate::error::ErrorCode;
+
+/// Serial the software backend reports for its single, virtual device.
+const SOFTWARE_SERIAL: Serial = Serial(0);
+
+/// Algorithm of a software key.
+///
+/// As in PIV slots, each key serves a single purpose: X25519 keys calculate agreements and
+/// Ed25519 keys sign. Software Ed25519 keys are X25519 private keys signing with XEdDSA, so that
+/// their signatures verify like those of a YubiKey against the X25519 form of the public key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    X25519,
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::X25519 => "x25519",
+            Self::Ed25519 => "ed25519",
+        }
+    }
+}
+
+/// Backend holding its keys in memory, standing in for a YubiKey on machines without one.
+///
+/// This offers none of the protection of a hardware token and is meant for testing only.
+/// Generated keys are lost when the server stops.
+#[derive(Default)]
+pub struct SoftwareBackend {
+    keys: HashMap<u8, (StaticSecret, KeyAlgorithm, KeyOrigin)>,
+}
+
+impl SoftwareBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads keys from a file with one `<slot> <hex private key> [<algorithm>]` entry per line.
+    ///
+    /// The algorithm is `x25519`, the default, or `ed25519`. Empty lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        info!("Loading software keys from {path:?}");
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read software keys at {path:?}"))?;
+
+        let mut backend = Self::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            backend
+                .parse_key_line(line)
+                .with_context(|| format!("{path:?}:{}", line_number + 1))?;
+        }
+        Ok(backend)
+    }
+
+    fn parse_key_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let mut fields = line.split_whitespace();
+        let slot = crate::slot::parse(fields.next().unwrap_or_default())?;
+        let private_key = fields
+            .next()
+            .ok_or(anyhow!("Failed to parse key: missing private key"))?;
+        let algorithm = match fields.next() {
+            None | Some("x25519") => KeyAlgorithm::X25519,
+            Some("ed25519") => KeyAlgorithm::Ed25519,
+            Some(other) => bail!("Failed to parse key: unknown algorithm {other:?}"),
+        };
+        if fields.next().is_some() {
+            bail!("Failed to parse key: trailing fields");
+        }
+        let private_key =
+            Zeroizing::new(hex::decode(private_key).context("Failed to parse private key")?);
+        let private_key: Zeroizing<[u8; 32]> =
+            Zeroizing::new(private_key.as_slice().try_into().map_err(|_| {
+                anyhow!(
+                    "Invalid length for private key. Expected '32', got: {}",
+                    private_key.len()
+                )
+            })?);
+        self.insert_key(
+            slot,
+            StaticSecret::from(*private_key),
+            algorithm,
+            KeyOrigin::Imported,
+        );
+        Ok(())
+    }
+
+    /// Stores `private_key` in `slot`, replacing any key already there.
+    pub fn insert_key(
+        &mut self,
+        slot: piv::SlotId,
+        private_key: StaticSecret,
+        algorithm: KeyAlgorithm,
+        origin: KeyOrigin,
+    ) {
+        let public_key = PublicKey::from(&private_key);
+        info!(
+            "Software {} key in slot {}: {}",
+            algorithm.name(),
+            crate::slot::name(slot),
+            hex::encode(public_key.as_bytes())
+        );
+        self.keys
+            .insert(u8::from(slot), (private_key, algorithm, origin));
+    }
+
+    fn key(&self, slot: piv::SlotId) -> anyhow::Result<&StaticSecret> {
+        self.key_with(slot, None)
+    }
+
+    /// Key in `slot`, which must have `algorithm` when given.
+    fn key_with(
+        &self,
+        slot: piv::SlotId,
+        algorithm: Option<KeyAlgorithm>,
+    ) -> anyhow::Result<&StaticSecret> {
+        match self.keys.get(&u8::from(slot)) {
+            Some((key, key_algorithm, _)) => match algorithm {
+                Some(algorithm) if algorithm != *key_algorithm => {
+                    let operation = match algorithm {
+                        KeyAlgorithm::X25519 => "Key agreement requires an X25519 key",
+                        KeyAlgorithm::Ed25519 => "Signing requires an Ed25519 key",
+                    };
+                    Err(ErrorCode::Unsupported.error(format!(
+                        "{operation}, not {} in slot {}",
+                        key_algorithm.name(),
+                        crate::slot::name(slot)
+                    )))
+                }
+                _ => Ok(key),
+            },
+            None => {
+                Err(ErrorCode::SlotEmpty
+                    .error(format!("No key in slot {}", crate::slot::name(slot))))
+            }
+        }
+    }
+}
+
+impl Backend for SoftwareBackend {
+    fn select(&mut self, serial: Option<Serial>) -> anyhow::Result<()> {
+        match serial {
+            Some(serial) if serial != SOFTWARE_SERIAL => {
+                Err(ErrorCode::DeviceAbsent.error(format!("Device unavailable: YubiKey {serial}")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn list_devices(&mut self) -> anyhow::Result<Vec<DeviceInfo>> {
+        Ok(vec![DeviceInfo {
+            serial: SOFTWARE_SERIAL,
+            version: "software".to_owned(),
+            reader: "software".to_owned(),
+        }])
+    }
+
+    fn list_slots(&mut self) -> anyhow::Result<Vec<SlotInfo>> {
+        Ok(crate::slot::SLOTS
+            .iter()
+            .map(|(_, slot)| SlotInfo {
+                slot: *slot,
+                key: match self.keys.get(&u8::from(*slot)) {
+                    Some((key, algorithm, origin)) => SlotKey::Key(KeyInfo {
+                        algorithm: algorithm.name().to_owned(),
+                        pin_policy: Some(PinPolicy::Never),
+                        touch_policy: Some(TouchPolicy::Never),
+                        origin: Some(*origin),
+                        public_key: Some(PublicKey::from(key).to_bytes()),
+                    }),
+                    None => SlotKey::Empty,
+                },
+            })
+            .collect())
+    }
+
+    fn status(&mut self) -> Status {
+        Status::Ready("software".to_owned())
+    }
+
+    fn requires_touch(&mut self, _slot: piv::SlotId) -> bool {
+        false
+    }
+
+    fn calculate_agreement(
+        &mut self,
+        slot: piv::SlotId,
+        their_key: &[u8; 32],
+    ) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let agreement = self
+            .key_with(slot, Some(KeyAlgorithm::X25519))?
+            .diffie_hellman(&PublicKey::from(*their_key));
+        Ok(Zeroizing::new(agreement.as_bytes().to_vec()))
+    }
+
+    fn public_key(&mut self, slot: piv::SlotId) -> anyhow::Result<[u8; 32]> {
+        Ok(PublicKey::from(self.key(slot)?).to_bytes())
+    }
+
+    fn verify_pin(&mut self, _pin: &[u8]) -> anyhow::Result<()> {
+        debug!("Software keys have no PIN, accepting any");
+        Ok(())
+    }
+
+    fn change_pin(&mut self, _current_pin: &[u8], _new_pin: &[u8]) -> anyhow::Result<()> {
+        Err(no_pin())
+    }
+
+    fn change_puk(&mut self, _current_puk: &[u8], _new_puk: &[u8]) -> anyhow::Result<()> {
+        Err(no_pin())
+    }
+
+    fn unblock_pin(&mut self, _puk: &[u8], _new_pin: &[u8]) -> anyhow::Result<()> {
+        Err(no_pin())
+    }
+
+    fn pin_retries(&mut self) -> anyhow::Result<u8> {
+        Err(no_pin())
+    }
+
+    fn rotate_management_key(&mut self) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>> {
+        Err(ErrorCode::Unsupported.error("Software keys have no management key"))
+    }
+
+    fn generate_key(
+        &mut self,
+        slot: piv::SlotId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> anyhow::Result<[u8; 32]> {
+        debug!("Ignoring PIN policy {pin_policy:?} and touch policy {touch_policy:?}");
+        let private_key = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&private_key);
+        self.insert_key(
+            slot,
+            private_key,
+            KeyAlgorithm::X25519,
+            KeyOrigin::Generated,
+        );
+        Ok(public_key.to_bytes())
+    }
+
+    fn import_key(
+        &mut self,
+        slot: piv::SlotId,
+        private_key: &[u8; 32],
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> anyhow::Result<()> {
+        debug!("Ignoring PIN policy {pin_policy:?} and touch policy {touch_policy:?}");
+        let private_key = Zeroizing::new(*private_key);
+        self.insert_key(
+            slot,
+            StaticSecret::from(*private_key),
+            KeyAlgorithm::X25519,
+            KeyOrigin::Imported,
+        );
+        Ok(())
+    }
+
+    fn sign(&mut self, slot: piv::SlotId, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let private_key =
+            Zeroizing::new(self.key_with(slot, Some(KeyAlgorithm::Ed25519))?.to_bytes());
+        Ok(xeddsa::sign(&private_key, message).to_vec())
+    }
+}
+
+fn no_pin() -> anyhow::Error {
+    ErrorCode::Unsupported.error("Software keys have no PIN or PUK")
+}
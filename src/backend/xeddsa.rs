@@ -0,0 +1,126 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! XEdDSA signatures, as specified by Signal: Ed25519-compatible signatures made with an
+//! X25519 private key, verifiable against its Montgomery public key.
+//!
+//! See <https://signal.org/docs/specifications/xeddsa/>.
+
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE,
+    scalar::{clamp_integer, Scalar},
+};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha512};
+use zeroize::Zeroizing;
+
+/// Signs `message` with the X25519 private key `private_key`, returning `R || s`.
+pub fn sign(private_key: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let mut nonce = Zeroizing::new([0u8; 64]);
+    OsRng.fill_bytes(nonce.as_mut());
+
+    // calculate_key_pair: the Edwards public key is forced to a sign bit of 0, negating the
+    // private scalar when needed.
+    let k = Zeroizing::new(Scalar::from_bytes_mod_order(clamp_integer(*private_key)));
+    let mut public_key = (&*k * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+    let sign_bit = public_key[31] >> 7;
+    public_key[31] &= 0x7F;
+    let a = Zeroizing::new(if sign_bit == 1 { -*k } else { *k });
+
+    // r = hash1(a || M || Z), hash1 prefixing its input with 2^256 - 2.
+    let mut prefix = [0xFFu8; 32];
+    prefix[0] = 0xFE;
+    let r = Zeroizing::new(hash_to_scalar(&[
+        &prefix,
+        a.as_bytes(),
+        message,
+        nonce.as_ref(),
+    ]));
+    let big_r = (&*r * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+    let h = hash_to_scalar(&[&big_r, &public_key, message]);
+    let s = *r + h * *a;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut digest = [0u8; 64];
+    digest.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::{edwards::CompressedEdwardsY, montgomery::MontgomeryPoint};
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::*;
+
+    /// Verifies `signature` as Signal does: against the Edwards form of the X25519
+    /// `public_key`, with a sign bit of 0.
+    fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+        let Some(a) = MontgomeryPoint(*public_key).to_edwards(0) else {
+            return false;
+        };
+        let Some(big_r) = CompressedEdwardsY::from_slice(&signature[..32])
+            .ok()
+            .and_then(|big_r| big_r.decompress())
+        else {
+            return false;
+        };
+        let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(
+            signature[32..].try_into().unwrap(),
+        )) else {
+            return false;
+        };
+        let digest = Sha512::new()
+            .chain_update(&signature[..32])
+            .chain_update(a.compress().as_bytes())
+            .chain_update(message)
+            .finalize();
+        let mut wide = [0u8; 64];
+        wide.copy_from_slice(&digest);
+        let h = Scalar::from_bytes_mod_order_wide(&wide);
+        &s * ED25519_BASEPOINT_TABLE == big_r + h * a
+    }
+
+    /// Sign bit of the Edwards public key of the X25519 `private_key`.
+    fn edwards_sign_bit(private_key: &[u8; 32]) -> u8 {
+        let k = Scalar::from_bytes_mod_order(clamp_integer(*private_key));
+        (&k * ED25519_BASEPOINT_TABLE).compress().to_bytes()[31] >> 7
+    }
+
+    #[test]
+    fn signatures_verify_for_both_sign_bits() {
+        let mut sign_bits = [false; 2];
+        for seed in 1..=16u8 {
+            let private_key = [seed; 32];
+            let public_key = PublicKey::from(&StaticSecret::from(private_key)).to_bytes();
+            sign_bits[usize::from(edwards_sign_bit(&private_key))] = true;
+
+            let signature = sign(&private_key, b"message");
+            assert!(verify(&public_key, b"message", &signature), "seed {seed}");
+            assert!(
+                !verify(&public_key, b"other message", &signature),
+                "seed {seed}"
+            );
+        }
+        assert_eq!(sign_bits, [true, true], "both sign bits must be covered");
+    }
+
+    #[test]
+    fn signatures_are_randomized() {
+        let private_key = [7; 32];
+        assert_ne!(
+            sign(&private_key, b"message"),
+            sign(&private_key, b"message")
+        );
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use anyhow::{bail, Context};
+use log::{debug, info};
+use x25519_dalek::{PublicKey, StaticSecret};
+use yubikey::{piv, PinPolicy, Serial, TouchPolicy};
+use zeroize::Zeroizing;
+
+use crate::{backend::Backend, error::ErrorCode, slot};
+
+/// Restrictions on the commands clients can run.
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Slots clients may use, all of them when `None`.
+    pub allowed_slots: Option<Vec<piv::SlotId>>,
+    /// Whether `import_key` is enabled.
+    pub allow_import_key: bool,
+}
+
+impl Policy {
+    fn check_slot(&self, slot: piv::SlotId) -> anyhow::Result<()> {
+        match &self.allowed_slots {
+            Some(allowed_slots) if !allowed_slots.contains(&slot) => {
+                Err(ErrorCode::AccessDenied
+                    .error(format!("Slot {} is not allowed", slot::name(slot))))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A command decoded from any protocol, with the device it targets.
+pub struct Request {
+    /// Serial of the device to use, the default one when `None`.
+    pub device: Option<Serial>,
+    pub command: Command,
+}
+
+pub enum Command {
+    /// Ends the connection, handled without reaching the backend.
+    Close,
+    Status,
+    ListDevices,
+    ListSlots,
+    CalculateAgreement {
+        slot: piv::SlotId,
+        their_key: [u8; 32],
+    },
+    Sign {
+        slot: piv::SlotId,
+        message: Vec<u8>,
+    },
+    GetPublicKey {
+        slot: piv::SlotId,
+    },
+    VerifyPin {
+        pin: Zeroizing<Vec<u8>>,
+    },
+    ChangePin {
+        current_pin: Zeroizing<Vec<u8>>,
+        new_pin: Zeroizing<Vec<u8>>,
+    },
+    ChangePuk {
+        current_puk: Zeroizing<Vec<u8>>,
+        new_puk: Zeroizing<Vec<u8>>,
+    },
+    UnblockPin {
+        puk: Zeroizing<Vec<u8>>,
+        new_pin: Zeroizing<Vec<u8>>,
+    },
+    PinRetries,
+    RotateManagementKey,
+    GenerateKey {
+        slot: piv::SlotId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    },
+    ImportKey {
+        slot: piv::SlotId,
+        private_key: Zeroizing<[u8; 32]>,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    },
+}
+
+impl Command {
+    /// Name of the command, as used by the text protocol.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Close => "close",
+            Command::Status => "status",
+            Command::ListDevices => "list_devices",
+            Command::ListSlots => "list_slots",
+            Command::CalculateAgreement { .. } => "calculate_agreement",
+            Command::Sign { .. } => "sign",
+            Command::GetPublicKey { .. } => "get_public_key",
+            Command::VerifyPin { .. } => "verify_pin",
+            Command::ChangePin { .. } => "change_pin",
+            Command::ChangePuk { .. } => "change_puk",
+            Command::UnblockPin { .. } => "unblock_pin",
+            Command::PinRetries => "pin_retries",
+            Command::RotateManagementKey => "rotate_management_key",
+            Command::GenerateKey { .. } => "generate_key",
+            Command::ImportKey { .. } => "import_key",
+        }
+    }
+
+    /// Whether only administrators may run the command.
+    pub fn requires_admin(&self) -> bool {
+        matches!(
+            self,
+            Command::GenerateKey { .. }
+                | Command::ImportKey { .. }
+                | Command::ChangePin { .. }
+                | Command::ChangePuk { .. }
+                | Command::UnblockPin { .. }
+                | Command::PinRetries
+                | Command::RotateManagementKey
+        )
+    }
+}
+
+/// Payload of a successful response.
+pub enum Payload {
+    /// Secret bytes, never logged.
+    Secret(Zeroizing<Vec<u8>>),
+    /// Public bytes, such as signatures.
+    Bytes(Vec<u8>),
+    /// Text.
+    Text(String),
+}
+
+/// Interim notification sent to the client while a command is executed.
+pub enum Notice {
+    /// The key in the slot waits for the user to touch the device.
+    TouchRequired(piv::SlotId),
+}
+
+/// Executes `request` on `backend`, returning the payload of the response. `notify` is
+/// called before operations the user must confirm.
+pub fn execute(
+    backend: &mut dyn Backend,
+    policy: &Policy,
+    request: &Request,
+    notify: &dyn Fn(Notice),
+) -> anyhow::Result<Payload> {
+    debug!("Handling command '{}'", request.command.name());
+    backend.select(request.device)?;
+    match &request.command {
+        Command::Close => bail!("close is handled by the connection"),
+        Command::Status => Ok(Payload::Text(backend.status().to_string())),
+        Command::ListDevices => handle_list_devices(backend)
+            .map(Payload::Text)
+            .context("handling list_devices command"),
+        Command::ListSlots => handle_list_slots(backend, policy)
+            .map(Payload::Text)
+            .context("handling list_slots command"),
+        Command::CalculateAgreement { slot, their_key } => {
+            handle_calculate_agreement(backend, policy, *slot, their_key, notify)
+                .map(Payload::Secret)
+                .context("handling calculate_agreement command")
+        }
+        Command::Sign { slot, message } => handle_sign(backend, policy, *slot, message, notify)
+            .map(Payload::Bytes)
+            .context("handling sign command"),
+        Command::GetPublicKey { slot } => handle_get_public_key(backend, policy, *slot)
+            .map(Payload::Bytes)
+            .context("handling get_public_key command"),
+        Command::VerifyPin { pin } => backend
+            .verify_pin(pin)
+            .map(|()| Payload::Text("verified".to_owned()))
+            .context("handling verify_pin command"),
+        Command::ChangePin {
+            current_pin,
+            new_pin,
+        } => backend
+            .change_pin(current_pin, new_pin)
+            .map(|()| Payload::Text("changed".to_owned()))
+            .context("handling change_pin command"),
+        Command::ChangePuk {
+            current_puk,
+            new_puk,
+        } => backend
+            .change_puk(current_puk, new_puk)
+            .map(|()| Payload::Text("changed".to_owned()))
+            .context("handling change_puk command"),
+        Command::UnblockPin { puk, new_pin } => backend
+            .unblock_pin(puk, new_pin)
+            .map(|()| Payload::Text("unblocked".to_owned()))
+            .context("handling unblock_pin command"),
+        Command::PinRetries => backend
+            .pin_retries()
+            .map(|retries| Payload::Text(retries.to_string()))
+            .context("handling pin_retries command"),
+        Command::RotateManagementKey => backend
+            .rotate_management_key()
+            .map(|key| match key {
+                Some(key) => Payload::Secret(key),
+                None => Payload::Text("pin-protected".to_owned()),
+            })
+            .context("handling rotate_management_key command"),
+        Command::GenerateKey {
+            slot,
+            pin_policy,
+            touch_policy,
+        } => handle_generate_key(backend, policy, *slot, *pin_policy, *touch_policy)
+            .map(Payload::Bytes)
+            .context("handling generate_key command"),
+        Command::ImportKey {
+            slot,
+            private_key,
+            pin_policy,
+            touch_policy,
+        } => handle_import_key(
+            backend,
+            policy,
+            *slot,
+            private_key,
+            *pin_policy,
+            *touch_policy,
+        )
+        .map(Payload::Bytes)
+        .context("handling import_key command"),
+    }
+}
+
+fn handle_calculate_agreement(
+    backend: &mut dyn Backend,
+    policy: &Policy,
+    slot: piv::SlotId,
+    their_key: &[u8; 32],
+    notify: &dyn Fn(Notice),
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    policy.check_slot(slot)?;
+    notify_touch(backend, slot, notify);
+    backend.calculate_agreement(slot, their_key)
+}
+
+fn handle_sign(
+    backend: &mut dyn Backend,
+    policy: &Policy,
+    slot: piv::SlotId,
+    message: &[u8],
+    notify: &dyn Fn(Notice),
+) -> anyhow::Result<Vec<u8>> {
+    policy.check_slot(slot)?;
+    notify_touch(backend, slot, notify);
+    backend.sign(slot, message)
+}
+
+fn notify_touch(backend: &mut dyn Backend, slot: piv::SlotId, notify: &dyn Fn(Notice)) {
+    if backend.requires_touch(slot) {
+        debug!("Waiting for a touch to use slot {}", slot::name(slot));
+        notify(Notice::TouchRequired(slot));
+    }
+}
+
+fn handle_get_public_key(
+    backend: &mut dyn Backend,
+    policy: &Policy,
+    slot: piv::SlotId,
+) -> anyhow::Result<Vec<u8>> {
+    policy.check_slot(slot)?;
+    Ok(signal_public_key(&backend.public_key(slot)?))
+}
+
+fn handle_generate_key(
+    backend: &mut dyn Backend,
+    policy: &Policy,
+    slot: piv::SlotId,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> anyhow::Result<Vec<u8>> {
+    policy.check_slot(slot)?;
+    let public_key = backend.generate_key(slot, pin_policy, touch_policy)?;
+    Ok(signal_public_key(&public_key))
+}
+
+fn handle_import_key(
+    backend: &mut dyn Backend,
+    policy: &Policy,
+    slot: piv::SlotId,
+    private_key: &[u8; 32],
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> anyhow::Result<Vec<u8>> {
+    if !policy.allow_import_key {
+        return Err(ErrorCode::AccessDenied.error("import_key is disabled"));
+    }
+    policy.check_slot(slot)?;
+    let expected = {
+        let private_key = Zeroizing::new(*private_key);
+        PublicKey::from(&StaticSecret::from(*private_key)).to_bytes()
+    };
+    backend.import_key(slot, private_key, pin_policy, touch_policy)?;
+    let public_key = backend.public_key(slot)?;
+    if public_key != expected {
+        bail!(
+            "Public key in slot {} does not match the imported key",
+            slot::name(slot)
+        );
+    }
+    info!("Imported key in slot {}", slot::name(slot));
+    Ok(signal_public_key(&public_key))
+}
+
+/// Formats an X25519 public key as Signal does, prefixed with its type, 0x05 for Curve25519.
+fn signal_public_key(public_key: &[u8; 32]) -> Vec<u8> {
+    let mut signal_key = vec![0x05];
+    signal_key.extend_from_slice(public_key);
+    signal_key
+}
+
+fn handle_list_devices(backend: &mut dyn Backend) -> anyhow::Result<String> {
+    let devices = backend.list_devices()?;
+    Ok(devices
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn handle_list_slots(backend: &mut dyn Backend, policy: &Policy) -> anyhow::Result<String> {
+    let slots = backend.list_slots()?;
+    Ok(slots
+        .iter()
+        .filter(|info| policy.check_slot(info.slot).is_ok())
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
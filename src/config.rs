@@ -0,0 +1,357 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Server configuration, read from the command line and an optional TOML file.
+//!
+//! Command line flags, which can also be given as environment variables, override the
+//! values of the file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use crate::{
+    backend::{ManagementKey, ManagementKeyAlgorithm},
+    command::Policy,
+    peer::AccessPolicy,
+    slot,
+    socket::{self, SocketConfig},
+};
+
+/// UDS server performing YubiKey operations for Signal clients.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file, `$XDG_CONFIG_HOME/signal-piv/config.toml` is used if present.
+    #[arg(short, long, env = "SIGNAL_PIV_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Validate the configuration and exit.
+    #[arg(long)]
+    check_config: bool,
+
+    /// Path of the socket [default: $XDG_RUNTIME_DIR/signal-piv.sock].
+    #[arg(long, env = "SIGNAL_PIV_SOCKET")]
+    socket: Option<PathBuf>,
+
+    /// Octal permissions of the socket file [default: 600].
+    #[arg(long, env = "SIGNAL_PIV_SOCKET_MODE")]
+    socket_mode: Option<String>,
+
+    /// Group, by name or gid, owning the socket file.
+    #[arg(long, env = "SIGNAL_PIV_SOCKET_GROUP")]
+    socket_group: Option<String>,
+
+    /// Serial number of the YubiKey to use.
+    #[arg(long, env = "SIGNAL_PIV_SERIAL")]
+    serial: Option<u32>,
+
+    /// File holding the hex-encoded management key of the YubiKey, needed to generate and
+    /// import keys, or `pin-protected` to use the key stored on the YubiKey [default: the
+    /// factory default key].
+    #[arg(long, env = "SIGNAL_PIV_MANAGEMENT_KEY_FILE")]
+    management_key_file: Option<PathBuf>,
+
+    /// Algorithm of the management key: 3des, aes128, aes192 or aes256 [default: inferred
+    /// from its length and the firmware version].
+    #[arg(long)]
+    management_key_algorithm: Option<String>,
+
+    /// Program printing the PIN on its standard output when it is needed, run as
+    /// `<helper> <serial> <slot>`.
+    #[arg(long, env = "SIGNAL_PIV_PIN_HELPER")]
+    pin_helper: Option<PathBuf>,
+
+    /// Log level: off, error, warn, info, debug or trace [default: RUST_LOG or info].
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+
+    /// Slots clients may use, e.g. `R1,R2` [default: all].
+    #[arg(long, value_delimiter = ',')]
+    allowed_slots: Option<Vec<String>>,
+
+    /// Uids allowed to connect [default: the server's own uid].
+    #[arg(long, env = "SIGNAL_PIV_ALLOWED_UIDS", value_delimiter = ',')]
+    allowed_uids: Option<Vec<u32>>,
+
+    /// Gids allowed to connect.
+    #[arg(long, env = "SIGNAL_PIV_ALLOWED_GIDS", value_delimiter = ',')]
+    allowed_gids: Option<Vec<u32>>,
+
+    /// Executables peers must be running, if any is given.
+    #[arg(long, env = "SIGNAL_PIV_ALLOWED_EXECUTABLES", value_delimiter = ':')]
+    allowed_executables: Option<Vec<PathBuf>>,
+
+    /// Uids allowed to run administrative commands, such as `import_key`.
+    #[arg(long, env = "SIGNAL_PIV_ADMIN_UIDS", value_delimiter = ',')]
+    admin_uids: Option<Vec<u32>>,
+
+    /// Gids allowed to run administrative commands.
+    #[arg(long, env = "SIGNAL_PIV_ADMIN_GIDS", value_delimiter = ',')]
+    admin_gids: Option<Vec<u32>>,
+
+    /// Enable the `import_key` command, for administrators only.
+    #[arg(long)]
+    allow_import_key: bool,
+
+    /// Hold keys from this file in memory instead of using a YubiKey. For testing only.
+    #[arg(long, env = "SIGNAL_PIV_SOFTWARE_KEYS")]
+    software_keys: Option<PathBuf>,
+
+    /// Log lengths and fingerprints of secrets. For debugging only.
+    #[arg(long, env = "SIGNAL_PIV_DEBUG_SECRETS")]
+    debug_secrets: bool,
+
+    /// Detach from the terminal and run in the background.
+    #[arg(long, conflicts_with = "foreground")]
+    daemon: bool,
+
+    /// Stay in the foreground, the default.
+    #[arg(long)]
+    foreground: bool,
+}
+
+/// Contents of the configuration file, every setting is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    socket: Option<PathBuf>,
+    socket_mode: Option<String>,
+    socket_group: Option<String>,
+    serial: Option<u32>,
+    management_key: Option<String>,
+    management_key_file: Option<PathBuf>,
+    management_key_algorithm: Option<String>,
+    pin_helper: Option<PathBuf>,
+    log_level: Option<String>,
+    allowed_slots: Option<Vec<String>>,
+    allowed_uids: Option<Vec<u32>>,
+    allowed_gids: Option<Vec<u32>>,
+    allowed_executables: Option<Vec<PathBuf>>,
+    admin_uids: Option<Vec<u32>>,
+    admin_gids: Option<Vec<u32>>,
+    allow_import_key: Option<bool>,
+    software_keys: Option<PathBuf>,
+    debug_secrets: Option<bool>,
+    daemon: Option<bool>,
+}
+
+impl FileConfig {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read configuration at {path:?}"))?;
+        toml::from_str(&contents).with_context(|| format!("invalid configuration at {path:?}"))
+    }
+}
+
+/// Validated configuration of the server.
+#[derive(Debug)]
+pub struct Config {
+    pub socket: SocketConfig,
+    pub access_policy: AccessPolicy,
+    pub policy: Policy,
+    pub serial: Option<yubikey::Serial>,
+    pub management_key: Option<ManagementKey>,
+    pub pin_helper: Option<PathBuf>,
+    /// Overrides `RUST_LOG` when set.
+    pub log_level: Option<LevelFilter>,
+    pub software_keys: Option<PathBuf>,
+    pub debug_secrets: bool,
+    pub daemon: bool,
+    /// Only validate the configuration, see `--check-config`.
+    pub check_only: bool,
+}
+
+impl Config {
+    /// Reads the configuration from the command line and the configuration file.
+    pub fn load() -> anyhow::Result<Self> {
+        let cli = Cli::parse();
+        let file = match cli.config.clone().or_else(default_file) {
+            Some(path) => FileConfig::load(&path)?,
+            None => FileConfig::default(),
+        };
+        Self::merge(cli, file)
+    }
+
+    fn merge(cli: Cli, file: FileConfig) -> anyhow::Result<Self> {
+        let mut socket = SocketConfig::default();
+        if let Some(path) = cli.socket.or(file.socket) {
+            socket.path = path;
+        }
+        if let Some(mode) = cli.socket_mode.or(file.socket_mode) {
+            socket.mode = u32::from_str_radix(&mode, 8)
+                .with_context(|| format!("Invalid socket mode: {mode}"))?;
+        }
+        if let Some(group) = cli.socket_group.or(file.socket_group) {
+            socket.group = Some(socket::resolve_group(&group)?);
+        }
+
+        let mut access_policy = AccessPolicy::current_user();
+        if let Some(uids) = cli.allowed_uids.or(file.allowed_uids) {
+            access_policy.uids = uids;
+        }
+        if let Some(gids) = cli.allowed_gids.or(file.allowed_gids) {
+            access_policy.gids = gids;
+        }
+        if let Some(executables) = cli.allowed_executables.or(file.allowed_executables) {
+            access_policy.executables = executables;
+        }
+        if let Some(uids) = cli.admin_uids.or(file.admin_uids) {
+            access_policy.admin_uids = uids;
+        }
+        if let Some(gids) = cli.admin_gids.or(file.admin_gids) {
+            access_policy.admin_gids = gids;
+        }
+
+        let mut policy = Policy::default();
+        if let Some(slots) = cli.allowed_slots.or(file.allowed_slots) {
+            let slots = slots
+                .iter()
+                .map(|name| slot::parse(name))
+                .collect::<anyhow::Result<_>>()
+                .context("Invalid allowed slots")?;
+            policy.allowed_slots = Some(slots);
+        }
+        policy.allow_import_key = cli.allow_import_key || file.allow_import_key.unwrap_or(false);
+
+        let management_key_algorithm = cli
+            .management_key_algorithm
+            .or(file.management_key_algorithm)
+            .map(|algorithm| ManagementKeyAlgorithm::parse(&algorithm))
+            .transpose()?;
+        // The key itself is never taken from the command line, which other users can read.
+        let management_key = match cli.management_key_file.or(file.management_key_file) {
+            Some(path) => Some(read_management_key(&path)?),
+            None => file.management_key.map(Zeroizing::new),
+        };
+        let management_key = management_key
+            .map(|key| ManagementKey::parse(&key, management_key_algorithm))
+            .transpose()?;
+
+        let log_level = match (cli.log_level, file.log_level) {
+            (Some(level), _) => Some(level),
+            (None, Some(level)) => Some(
+                level
+                    .parse()
+                    .with_context(|| format!("Invalid log level: {level}"))?,
+            ),
+            (None, None) => None,
+        };
+
+        let daemon = if cli.daemon {
+            true
+        } else if cli.foreground {
+            false
+        } else {
+            file.daemon.unwrap_or(false)
+        };
+
+        Ok(Self {
+            socket,
+            access_policy,
+            policy,
+            serial: cli.serial.or(file.serial).map(yubikey::Serial::from),
+            management_key,
+            pin_helper: cli.pin_helper.or(file.pin_helper),
+            log_level,
+            software_keys: cli.software_keys.or(file.software_keys),
+            debug_secrets: cli.debug_secrets || file.debug_secrets.unwrap_or(false),
+            daemon,
+            check_only: cli.check_config,
+        })
+    }
+}
+
+/// Reads the management key from the file at `path`.
+fn read_management_key(path: &Path) -> anyhow::Result<Zeroizing<String>> {
+    std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .with_context(|| format!("could not read management key at {path:?}"))
+}
+
+/// `$XDG_CONFIG_HOME/signal-piv/config.toml`, if it exists.
+fn default_file() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) => PathBuf::from(config_home),
+        None => Path::new(&std::env::var_os("HOME")?).join(".config"),
+    };
+    let path = config_home.join("signal-piv").join("config.toml");
+    path.is_file().then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("signal-piv").chain(args.iter().copied())).unwrap()
+    }
+
+    fn file(contents: &str) -> FileConfig {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let file = file(
+            r#"
+            socket = "/file.sock"
+            serial = 1
+            allowed_uids = [1000]
+            log_level = "debug"
+            "#,
+        );
+        let config = Config::merge(cli(&["--socket", "/cli.sock", "--serial", "2"]), file).unwrap();
+
+        assert_eq!(config.socket.path, Path::new("/cli.sock"));
+        assert_eq!(config.serial, Some(yubikey::Serial(2)));
+        assert_eq!(config.access_policy.uids, [1000]);
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn prefers_management_key_file() {
+        let path = std::env::temp_dir().join(format!("signal-piv-test-{}.key", std::process::id()));
+        std::fs::write(&path, format!("{}\n", "01".repeat(24))).unwrap();
+        let file = file(r#"management_key = "pin-protected""#);
+
+        let config = Config::merge(
+            cli(&["--management-key-file", path.to_str().unwrap()]),
+            file,
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            config.unwrap().management_key,
+            Some(ManagementKey::Manual { ref key, .. }) if key.len() == 24
+        ));
+    }
+
+    #[test]
+    fn resolves_daemon_mode() {
+        let daemon = |args: &[&str], file_daemon| {
+            let file = FileConfig {
+                daemon: file_daemon,
+                ..FileConfig::default()
+            };
+            Config::merge(cli(args), file).unwrap().daemon
+        };
+
+        assert!(!daemon(&[], None));
+        assert!(daemon(&[], Some(true)));
+        assert!(daemon(&["--daemon"], Some(false)));
+        assert!(!daemon(&["--foreground"], Some(true)));
+        assert!(Cli::try_parse_from(["signal-piv", "--daemon", "--foreground"]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(Config::merge(cli(&["--socket-mode", "9"]), FileConfig::default()).is_err());
+        assert!(Config::merge(cli(&[]), file(r#"log_level = "loud""#)).is_err());
+        assert!(toml::from_str::<FileConfig>("unknown = 1").is_err());
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, error, info, warn};
+use zeroize::Zeroizing;
+
+use crate::{
+    command::{Command, Notice, Payload},
+    error::ErrorCode,
+    frame,
+    peer::{AccessPolicy, PeerCredentials},
+    protocol::{binary, Protocol, RequestId},
+    redact, slot,
+    worker::Worker,
+};
+
+/// Connections without any command for this long are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Number of requests a connection can have in flight before new ones are rejected.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Frames waiting to be written to the peer.
+type Frames = Sender<Zeroizing<Vec<u8>>>;
+
+/// Number of requests of a connection submitted to the worker and not answered yet.
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    answered: Condvar,
+}
+
+impl InFlight {
+    /// Counts a new request, unless [`MAX_IN_FLIGHT`] are already in flight.
+    fn try_add(&self) -> bool {
+        let mut count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        if *count >= MAX_IN_FLIGHT {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Records that a request was answered.
+    fn remove(&self) {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.answered.notify_all();
+    }
+
+    /// Waits until every request in flight was answered.
+    fn wait_answered(&self) {
+        let count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        let _count = self
+            .answered
+            .wait_while(count, |count| *count > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// Serves the commands sent on `unix_stream` until the peer closes the connection, sends
+/// the `close` command or stays idle for [`IDLE_TIMEOUT`].
+///
+/// Each connection is served by its own thread; commands are executed by `worker`. Commands
+/// are read without waiting for the previous ones to complete, and responses are written by a
+/// separate thread as soon as they are ready, possibly out of order when requests carry ids.
+/// Peers not allowed by `access_policy` are sent an error and disconnected.
+pub fn serve(
+    worker: &Worker,
+    access_policy: &AccessPolicy,
+    mut unix_stream: UnixStream,
+) -> anyhow::Result<()> {
+    let peer = PeerCredentials::of(&unix_stream)?;
+    if let Err(reason) = access_policy.check(&peer) {
+        warn!("Rejected connection: {peer} reason=\"{reason:#}\"");
+        let rejection =
+            Protocol::Text.encode_error(None, ErrorCode::AccessDenied, &anyhow!("Access denied"));
+        unix_stream
+            .write_all(&rejection)
+            .context("Failed to write rejection")?;
+        return Ok(());
+    }
+    let admin = access_policy.is_admin(&peer);
+    debug!("Handling new connection: {peer} admin={admin}");
+
+    // Commands and responses are read and written without intermediate buffering, so that
+    // the secrets they may hold only live in buffers wiped on drop.
+    let mut buf = Zeroizing::new([0u8; frame::MAX_COMMAND_LEN]);
+    let mut reader = unix_stream
+        .try_clone()
+        .context("Failed to duplicate handle on UDS")?;
+    unix_stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .context("Failed to set idle timeout on UDS")?;
+    let (frames, pending_frames) = mpsc::channel::<Zeroizing<Vec<u8>>>();
+    let writer = thread::Builder::new()
+        .name("connection-writer".to_owned())
+        .spawn(move || {
+            let mut writer = unix_stream;
+            for frame in pending_frames {
+                if let Err(err) = writer.write_all(&frame) {
+                    error!("Failed to write response: {err}");
+                    // Unblocks the reader, there is no point reading commands anymore.
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        })
+        .context("Failed to spawn connection writer")?;
+
+    let in_flight = Arc::new(InFlight::default());
+    let mut protocol = Protocol::Text;
+    let mut first_frame = true;
+    loop {
+        let mut command_len_buf = [0u8; 4];
+        if let Err(err) = reader.read_exact(&mut command_len_buf) {
+            match err.kind() {
+                std::io::ErrorKind::UnexpectedEof => debug!("Connection closed by peer"),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    info!("Closing idle connection")
+                }
+                _ => error!("Failed to read command length: {err}"),
+            }
+            break;
+        }
+        let command_len = u32::from_le_bytes(command_len_buf) as usize;
+        if command_len > frame::MAX_COMMAND_LEN {
+            // The connection cannot be resynchronized without reading the whole command, so
+            // it is closed after replying.
+            warn!("Rejecting command of {command_len} bytes");
+            let err = ErrorCode::InvalidRequest.error(format!(
+                "Command too long: {command_len} bytes, the maximum is {}",
+                frame::MAX_COMMAND_LEN
+            ));
+            reject(
+                &frames,
+                protocol,
+                &in_flight,
+                None,
+                ErrorCode::InvalidRequest,
+                err,
+            );
+            break;
+        }
+        let command_buf = &mut buf[..command_len];
+        if let Err(err) = reader.read_exact(command_buf) {
+            error!("Failed to read command: {err}");
+            break;
+        }
+
+        if std::mem::take(&mut first_frame) && binary::is_handshake(command_buf) {
+            let version = binary::negotiate(command_buf);
+            if frames.send(binary::encode_handshake(version)).is_err() {
+                break;
+            }
+            match version {
+                Some(version) => {
+                    debug!("Negotiated binary protocol version {version}");
+                    protocol = Protocol::Binary { version };
+                    continue;
+                }
+                None => {
+                    warn!("No common binary protocol version with the client");
+                    break;
+                }
+            }
+        }
+
+        let (id, request) = protocol.decode(command_buf);
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let code = ErrorCode::find(&err).unwrap_or(ErrorCode::InvalidRequest);
+                reject(&frames, protocol, &in_flight, id, code, err);
+                continue;
+            }
+        };
+        if let Command::Close = request.command {
+            debug!("Connection closed on request");
+            break;
+        }
+
+        if request.command.requires_admin() && !admin {
+            warn!(
+                "Rejected {} command from non-admin {peer}",
+                request.command.name()
+            );
+            let err = ErrorCode::AccessDenied.error(format!(
+                "{} is restricted to administrators",
+                request.command.name()
+            ));
+            reject(
+                &frames,
+                protocol,
+                &in_flight,
+                id,
+                ErrorCode::AccessDenied,
+                err,
+            );
+            continue;
+        }
+
+        if !in_flight.try_add() {
+            let err = ErrorCode::Busy.error("Too many requests in flight on this connection");
+            reject(&frames, protocol, &in_flight, id, ErrorCode::Busy, err);
+            continue;
+        }
+        let notify = {
+            let frames = frames.clone();
+            let sends_notices = protocol.sends_notices(id);
+            move |notice: Notice| {
+                if !sends_notices {
+                    return;
+                }
+                let Notice::TouchRequired(slot) = &notice;
+                debug!("[sending] notice touch_required {}", slot::name(*slot));
+                let _ = frames.send(protocol.encode_notice(id, &notice));
+            }
+        };
+        let reply = {
+            let frames = frames.clone();
+            let in_flight = in_flight.clone();
+            move |result: anyhow::Result<Payload>| {
+                let result = result.map_err(|err| {
+                    let code = ErrorCode::find(&err).unwrap_or(ErrorCode::Internal);
+                    (code, err)
+                });
+                respond(&frames, protocol, id, result);
+                // Only once the response is queued, so that rejections waiting for it follow it.
+                in_flight.remove();
+            }
+        };
+        if let Err(err) = worker.submit(request, notify, reply) {
+            in_flight.remove();
+            let code = ErrorCode::find(&err).unwrap_or(ErrorCode::Internal);
+            reject(&frames, protocol, &in_flight, id, code, err);
+        }
+    }
+
+    // The writer stops once every response still in flight was written and the last sender
+    // is dropped.
+    drop(frames);
+    if writer.join().is_err() {
+        error!("Connection writer panicked");
+    }
+    Ok(())
+}
+
+/// Queues the error response to request `id`, rejected before reaching the worker.
+///
+/// Responses to requests without an id must follow the order of the requests, so the
+/// responses to the requests still in flight are queued first.
+fn reject(
+    frames: &Frames,
+    protocol: Protocol,
+    in_flight: &InFlight,
+    id: Option<RequestId>,
+    code: ErrorCode,
+    err: anyhow::Error,
+) {
+    if id.is_none() {
+        in_flight.wait_answered();
+    }
+    respond(frames, protocol, id, Err((code, err)));
+}
+
+/// Encodes the response to request `id` and queues it for writing.
+fn respond(
+    frames: &Frames,
+    protocol: Protocol,
+    id: Option<RequestId>,
+    result: Result<Payload, (ErrorCode, anyhow::Error)>,
+) {
+    let response = match result {
+        Ok(payload) => {
+            match &payload {
+                Payload::Secret(secret) => {
+                    debug!("[sending] success {}", redact::Secret(secret))
+                }
+                Payload::Bytes(bytes) => debug!("[sending] success {}", hex::encode(bytes)),
+                Payload::Text(text) => debug!("[sending] success {text}"),
+            }
+            protocol.encode_success(id, &payload)
+        }
+        Err((code, err)) => {
+            error!("Failed to handle command: {err}");
+            debug!("[sending] error {code} {err}");
+            protocol.encode_error(id, code, &err)
+        }
+    };
+    // Fails only once the writer stopped, after logging why.
+    let _ = frames.send(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::JoinHandle;
+
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::*;
+    use crate::{
+        backend::{KeyOrigin, SoftwareBackend, SoftwareKeyAlgorithm},
+        command::Policy,
+    };
+
+    /// Serves one end of a socket pair with a software key in slot `R1`, returning the other
+    /// end, the key and the thread serving the connection.
+    fn connect() -> (UnixStream, StaticSecret, JoinHandle<anyhow::Result<()>>) {
+        let key = StaticSecret::from([0x42; 32]);
+        let mut backend = SoftwareBackend::new();
+        backend.insert_key(
+            slot::parse("R1").unwrap(),
+            key.clone(),
+            SoftwareKeyAlgorithm::X25519,
+            KeyOrigin::Imported,
+        );
+        let worker = Worker::spawn(Box::new(backend), Policy::default()).unwrap();
+        let (client, server) = UnixStream::pair().unwrap();
+        let connection =
+            thread::spawn(move || serve(&worker, &AccessPolicy::current_user(), server));
+        (client, key, connection)
+    }
+
+    fn send(stream: &mut UnixStream, body: &[u8]) {
+        stream.write_all(&frame::encode(body)).unwrap();
+    }
+
+    /// Reads the body of the next frame, `None` once the server closed the connection.
+    fn receive(stream: &mut UnixStream) -> Option<String> {
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len) {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+            result => result.unwrap(),
+        }
+        let mut body = vec![0u8; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut body).unwrap();
+        Some(String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn calculates_agreement() {
+        let (mut client, key, connection) = connect();
+        let their_key = PublicKey::from(&StaticSecret::from([0x24; 32]));
+        let shared_secret = key.diffie_hellman(&their_key);
+
+        send(
+            &mut client,
+            format!(
+                "calculate_agreement R1 05{} ",
+                hex::encode(their_key.as_bytes())
+            )
+            .as_bytes(),
+        );
+        assert_eq!(
+            receive(&mut client).unwrap(),
+            format!("success {}", hex::encode(shared_secret.as_bytes()))
+        );
+
+        send(
+            &mut client,
+            format!(
+                "#7 calculate_agreement R1 05{} ",
+                hex::encode(their_key.as_bytes())
+            )
+            .as_bytes(),
+        );
+        assert_eq!(
+            receive(&mut client).unwrap(),
+            format!("#7 success {}", hex::encode(shared_secret.as_bytes()))
+        );
+
+        drop(client);
+        connection.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn reports_invalid_commands() {
+        let (mut client, _, connection) = connect();
+
+        send(&mut client, b"calculate_agreement R1 05 ");
+        assert!(receive(&mut client).unwrap().starts_with("error bad_key "));
+        send(&mut client, b"#3 frobnicate");
+        assert!(receive(&mut client)
+            .unwrap()
+            .starts_with("#3 error unknown_command "));
+
+        drop(client);
+        connection.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn refuses_to_sign_with_agreement_keys() {
+        let (mut client, _, connection) = connect();
+
+        send(&mut client, b"sign R1 00");
+        let response = receive(&mut client).unwrap();
+        assert!(response.starts_with("error unsupported "), "{response}");
+        assert!(response.contains("requires an Ed25519 key, not x25519 in slot R1"));
+
+        drop(client);
+        connection.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn answers_requests_without_ids_in_order() {
+        let (mut client, key, connection) = connect();
+        let their_key = PublicKey::from(&StaticSecret::from([0x24; 32]));
+        let shared_secret = key.diffie_hellman(&their_key);
+
+        send(
+            &mut client,
+            format!(
+                "calculate_agreement R1 05{} ",
+                hex::encode(their_key.as_bytes())
+            )
+            .as_bytes(),
+        );
+        send(&mut client, b"frobnicate");
+        assert_eq!(
+            receive(&mut client).unwrap(),
+            format!("success {}", hex::encode(shared_secret.as_bytes()))
+        );
+        assert!(receive(&mut client)
+            .unwrap()
+            .starts_with("error unknown_command "));
+
+        drop(client);
+        connection.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn closes_on_oversized_frame() {
+        let (mut client, _, connection) = connect();
+
+        let len = u32::try_from(frame::MAX_COMMAND_LEN + 1).unwrap();
+        client.write_all(&len.to_le_bytes()).unwrap();
+        assert!(receive(&mut client)
+            .unwrap()
+            .starts_with("error invalid_request "));
+        assert_eq!(receive(&mut client), None);
+        connection.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn closes_on_request() {
+        let (mut client, _, connection) = connect();
+
+        send(&mut client, b"status");
+        assert_eq!(receive(&mut client).unwrap(), "success ready software");
+        send(&mut client, b"close");
+        assert_eq!(receive(&mut client), None);
+        connection.join().unwrap().unwrap();
+    }
+}
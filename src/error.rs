@@ -0,0 +1,114 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::fmt;
+
+/// Stable codes telling clients why a command failed.
+///
+/// Codes are sent by name in text responses and by value in binary ones; neither may
+/// change once released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// The command could not be parsed.
+    InvalidRequest = 1,
+    /// The command is not known.
+    UnknownCommand = 2,
+    /// A key given by the client is malformed.
+    BadKey = 3,
+    /// The slot holds no key.
+    SlotEmpty = 4,
+    /// The PIN must be verified before using the key.
+    PinRequired = 5,
+    /// The PIN is blocked and must be unblocked with the PUK.
+    PinBlocked = 6,
+    /// The device is not connected.
+    DeviceAbsent = 7,
+    /// The key requires a touch, which did not happen in time.
+    TouchTimeout = 8,
+    /// The client is not allowed to perform the command.
+    AccessDenied = 9,
+    /// Too many commands are queued, the client should retry later.
+    Busy = 10,
+    /// The device or backend does not support the command.
+    Unsupported = 11,
+    /// The PIN or PUK given is wrong.
+    WrongPin = 12,
+    /// Any other failure.
+    Internal = 255,
+}
+
+impl ErrorCode {
+    /// Builds an error with this code and `message`.
+    ///
+    /// The code stays retrievable with [`ErrorCode::find`] when context is added to the
+    /// error.
+    pub fn error(self, message: impl fmt::Display) -> anyhow::Error {
+        anyhow::Error::new(CodedError {
+            code: self,
+            message: message.to_string(),
+        })
+    }
+
+    /// Returns the code `err` was built with, if any.
+    pub fn find(err: &anyhow::Error) -> Option<Self> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<CodedError>())
+            .map(|coded| coded.code)
+    }
+
+    /// Name of the code as sent in text responses.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::BadKey => "bad_key",
+            ErrorCode::SlotEmpty => "slot_empty",
+            ErrorCode::PinRequired => "pin_required",
+            ErrorCode::PinBlocked => "pin_blocked",
+            ErrorCode::DeviceAbsent => "device_absent",
+            ErrorCode::TouchTimeout => "touch_timeout",
+            ErrorCode::AccessDenied => "access_denied",
+            ErrorCode::Busy => "busy",
+            ErrorCode::Unsupported => "unsupported",
+            ErrorCode::WrongPin => "wrong_pin",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error carrying an [`ErrorCode`], displayed as its message only.
+#[derive(Debug)]
+struct CodedError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Converts an error reported by a YubiKey to an error with the matching code.
+pub fn from_yubikey(err: yubikey::Error) -> anyhow::Error {
+    if let yubikey::Error::WrongPin { tries: tries @ 1.. } = err {
+        return ErrorCode::WrongPin.error(format!("Wrong PIN or PUK, {tries} tries left"));
+    }
+    let code = match err {
+        yubikey::Error::AuthenticationError => ErrorCode::PinRequired,
+        yubikey::Error::PinLocked | yubikey::Error::WrongPin { .. } => ErrorCode::PinBlocked,
+        yubikey::Error::NotFound => ErrorCode::SlotEmpty,
+        yubikey::Error::PcscError { .. } => ErrorCode::DeviceAbsent,
+        _ => ErrorCode::Internal,
+    };
+    code.error(err)
+}
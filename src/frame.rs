@@ -0,0 +1,33 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Length-prefixed frames exchanged on the socket.
+//!
+//! Every frame is a little-endian `u32` length followed by that many bytes of body, whose
+//! format depends on the protocol, see [`crate::protocol`].
+
+use zeroize::Zeroizing;
+
+const LEN_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Largest command accepted from clients, in bytes, excluding the length prefix.
+///
+/// Longer commands get an error response and their connection is closed.
+pub const MAX_COMMAND_LEN: usize = 8192;
+
+/// Builds a frame with a body of `body_len` bytes, written by `fill`.
+///
+/// Writing the body in place means the frame is the only copy of it this produces, and it
+/// is wiped on drop.
+pub fn build(body_len: usize, fill: impl FnOnce(&mut [u8])) -> Zeroizing<Vec<u8>> {
+    let len = u32::try_from(body_len).expect("responses are far smaller than 4 GiB");
+    let mut frame = Zeroizing::new(vec![0u8; LEN_SIZE + body_len]);
+    frame[..LEN_SIZE].copy_from_slice(&len.to_le_bytes());
+    fill(&mut frame[LEN_SIZE..]);
+    frame
+}
+
+/// Builds a frame with `body`.
+pub fn encode(body: &[u8]) -> Zeroizing<Vec<u8>> {
+    build(body.len(), |frame_body| frame_body.copy_from_slice(body))
+}
@@ -0,0 +1,81 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! PIN and touch policies of keys, as given by clients.
+
+use anyhow::bail;
+use yubikey::{PinPolicy, TouchPolicy};
+
+/// PIN policies with the name and PIV id clients refer to them by.
+const PIN_POLICIES: [(&str, u8, PinPolicy); 4] = [
+    ("default", 0x00, PinPolicy::Default),
+    ("never", 0x01, PinPolicy::Never),
+    ("once", 0x02, PinPolicy::Once),
+    ("always", 0x03, PinPolicy::Always),
+];
+
+/// Touch policies with the name and PIV id clients refer to them by.
+const TOUCH_POLICIES: [(&str, u8, TouchPolicy); 4] = [
+    ("default", 0x00, TouchPolicy::Default),
+    ("never", 0x01, TouchPolicy::Never),
+    ("always", 0x02, TouchPolicy::Always),
+    ("cached", 0x03, TouchPolicy::Cached),
+];
+
+/// Parses a PIN policy by name, case insensitively.
+pub fn parse_pin_policy(name: &str) -> anyhow::Result<PinPolicy> {
+    match PIN_POLICIES
+        .iter()
+        .find(|(other, _, _)| other.eq_ignore_ascii_case(name))
+    {
+        Some((_, _, policy)) => Ok(*policy),
+        None => {
+            bail!("Invalid PIN policy: {name}. Valid policies are default, never, once and always")
+        }
+    }
+}
+
+/// Returns the PIN policy with the given PIV id.
+pub fn pin_policy_from_id(id: u8) -> anyhow::Result<PinPolicy> {
+    match PIN_POLICIES.iter().find(|(_, other, _)| *other == id) {
+        Some((_, _, policy)) => Ok(*policy),
+        None => bail!("Invalid PIN policy id: {id:#04x}"),
+    }
+}
+
+/// Parses a touch policy by name, case insensitively.
+pub fn parse_touch_policy(name: &str) -> anyhow::Result<TouchPolicy> {
+    match TOUCH_POLICIES
+        .iter()
+        .find(|(other, _, _)| other.eq_ignore_ascii_case(name))
+    {
+        Some((_, _, policy)) => Ok(*policy),
+        None => bail!(
+            "Invalid touch policy: {name}. Valid policies are default, never, always and cached"
+        ),
+    }
+}
+
+/// Returns the touch policy with the given PIV id.
+pub fn touch_policy_from_id(id: u8) -> anyhow::Result<TouchPolicy> {
+    match TOUCH_POLICIES.iter().find(|(_, other, _)| *other == id) {
+        Some((_, _, policy)) => Ok(*policy),
+        None => bail!("Invalid touch policy id: {id:#04x}"),
+    }
+}
+
+/// Name clients refer to `policy` by.
+pub fn pin_policy_name(policy: PinPolicy) -> &'static str {
+    PIN_POLICIES
+        .iter()
+        .find(|(_, _, other)| *other == policy)
+        .map_or("unknown", |(name, _, _)| name)
+}
+
+/// Name clients refer to `policy` by.
+pub fn touch_policy_name(policy: TouchPolicy) -> &'static str {
+    TOUCH_POLICIES
+        .iter()
+        .find(|(_, _, other)| *other == policy)
+        .map_or("unknown", |(name, _, _)| name)
+}
@@ -1,152 +1,128 @@
 // Copyright (c) SandboxAQ. All rights reserved.
 // SPDX-License-Identifier: AGPL-3.0-only
 
+mod backend;
+mod command;
+mod config;
+mod connection;
+mod error;
+mod frame;
+mod key_policy;
+mod peer;
+mod protocol;
+mod redact;
+mod slot;
+mod socket;
+mod worker;
+
 use std::{
-    io::{BufReader, BufWriter, Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
-use anyhow::{anyhow, bail, Context};
-use log::{debug, error, info};
-use yubikey::{piv, YubiKey};
+use anyhow::Context;
+use log::{error, info};
 
+use backend::{Backend, ManagementKey, SoftwareBackend, YubiKeyBackend};
+use config::Config;
+use worker::Worker;
 
+/// Pause after failing to accept a connection, to avoid spinning while the cause persists.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
-
-    let unix_listener = initialize_uds()?;
-
-    let mut yubikey = YubiKey::open()
-        .context("Failed to open yubikey device")
-        .unwrap();
-
-    let transaction = yubikey
-        .begin_transaction()
-        .context("Failed to create transaction")?;
+    let config = Config::load()?;
+    if config.check_only {
+        if let Some(path) = &config.software_keys {
+            SoftwareBackend::from_file(path)?;
+        }
+        println!("Configuration is valid: {config:#?}");
+        return Ok(());
+    }
 
-    loop {
-        let (unix_stream, _socket_address) = unix_listener
-            .accept()
-            .context("Failed at accepting a connection on the unix listener")?;
-        handle_stream(&transaction, unix_stream)?;
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(log_level) = config.log_level {
+        logger.filter_level(log_level);
+    }
+    logger.init();
+    if config.debug_secrets {
+        redact::enable_debug();
     }
-}
 
-fn initialize_uds() -> anyhow::Result<UnixListener> {
-    info!("Starting UDS listener");
-    let socket_path = "/tmp/signal-piv.sock";
+    info!("Access policy: {:?}", config.access_policy);
+    let access_policy = Arc::new(config.access_policy);
 
-    if std::fs::metadata(socket_path).is_ok() {
-        info!("A socket is already present. Deleting...");
-        std::fs::remove_file(socket_path)
-            .with_context(|| format!("could not delete previous socket at {:?}", socket_path))?;
-    }
+    let unix_listener = socket::bind(&config.socket)?;
 
-    UnixListener::bind(socket_path).context("Could not create the unix socket")
-}
+    if config.daemon {
+        daemonize()?;
+    }
 
-fn handle_stream(
-    transaction: &yubikey::Transaction,
-    unix_stream: UnixStream,
-) -> anyhow::Result<()> {
-    debug!("Handling new connection");
+    let backend = initialize_backend(
+        config.software_keys.as_deref(),
+        config.serial,
+        config.management_key,
+        config.pin_helper,
+    )?;
+    let worker = Worker::spawn(backend, config.policy)?;
 
-    let mut buf = [0u8; 8192];
-    let mut reader = BufReader::new(
-        unix_stream
-            .try_clone()
-            .context("Failed to duplicate handle on UDS")?,
-    );
-    let mut writer = BufWriter::new(unix_stream);
     loop {
-        let mut command_len_buf = [0u8; 4];
-        if let Err(err) = reader.read_exact(&mut command_len_buf) {
-            error!("Failed to read command length: {err}");
-            if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                break;
-            }
-            break;
-        }
-        let command_len = u32::from_le_bytes(command_len_buf) as usize;
-        let mut command_buf = &mut buf[..command_len];
-        if let Err(err) = reader.read_exact(&mut command_buf) {
-            error!("Failed to read command: {err}");
-            if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                break;
-            }
-            break;
-        }
-        let command = match String::from_utf8(command_buf.to_vec()) {
-            Ok(command) => command,
-            Err(err) => {
-                error!("Failed to parse command: {err}");
-                break;
-            }
-        };
-
-        let response = match handle_command(transaction, &command) {
-            Ok(agreement) => format!("success {}", hex::encode(&agreement)),
+        // Failures here, e.g. running out of file descriptors, are caused by clients and must
+        // not stop the server.
+        let unix_stream = match unix_listener.accept() {
+            Ok((unix_stream, _socket_address)) => unix_stream,
             Err(err) => {
-                error!("Failed to handle command: {err}");
-                format!("error {err}")
+                error!("Failed at accepting a connection on the unix listener: {err}");
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
             }
         };
-        log::info!("[sending] {response}");
-        let response = response.into_bytes();
-        let len = u32::try_from(response.len()).unwrap();
-        if let Err(err) = writer.write_all(&len.to_le_bytes()) {
-            error!("Failed to write response len: {err}");
-            break;
-        }
-        if let Err(err) = writer.write_all(&response) {
-            error!("Failed to write response: {err}");
-            break;
+        let worker = worker.clone();
+        let access_policy = access_policy.clone();
+        let spawned = thread::Builder::new()
+            .name("connection".to_owned())
+            .spawn(move || {
+                if let Err(err) = connection::serve(&worker, &access_policy, unix_stream) {
+                    error!("Failed to handle connection: {err:#}");
+                }
+            });
+        if let Err(err) = spawned {
+            error!("Failed to spawn connection handler: {err}");
         }
-        break;
     }
-
-    Ok(())
 }
 
-fn handle_command(transaction: &yubikey::Transaction, command: &str) -> anyhow::Result<Vec<u8>> {
-    debug!("Handling command '{command}'");
-    let (command_code, command_body) = command.split_once(" ").ok_or_else(|| anyhow!("Failed to get command_code: {command}"))?;
-    match command_code {
-        "calculate_agreement" => handle_calculate_agreement(transaction, command_body).context("handling calculate_agreement command"),
-        _ => bail!("Unknown command: {command_code}"),
+/// Detaches the server from its terminal. Must be called before spawning any thread.
+///
+/// The working directory and standard streams are kept, so relative paths keep working and
+/// logs can still be redirected.
+fn daemonize() -> anyhow::Result<()> {
+    info!("Detaching from the terminal");
+    // SAFETY: no other thread is running yet.
+    if unsafe { libc::daemon(1, 1) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to daemonize");
     }
+    Ok(())
 }
 
-fn handle_calculate_agreement(transaction: &yubikey::Transaction, command_body: &str) -> anyhow::Result<Vec<u8>> {
-    let (key_slot, command_body) = command_body.split_once(" ").ok_or(anyhow!("Failed to parse command: missing 'our_key'"))?;
-
-    let (their_key, command_body) = command_body.split_once(" ").ok_or(anyhow!("Failed to parse command: missing 'their_key'"))?;
-
-    if command_body != "" {
-        bail!("Failed to parse command, unexpected data at the end of the body: {command_body}")
-    }
-    
-    let key_slot = match key_slot {
-        "R1" => piv::SlotId::Retired(piv::RetiredSlotId::R1),
-        "R2" => piv::SlotId::Retired(piv::RetiredSlotId::R2),
-        other => bail!("Invalid slot id: {other}"),
-    };
-
-    let their_key = hex::decode(&their_key).context("Failed to parse 'their_key'")?;
-    if their_key.len() != 33 {
-        bail!(
-            "Invalid length for 'their_key'. Expected '33', got: {}",
-            their_key.len()
-        );
+fn initialize_backend(
+    software_keys: Option<&Path>,
+    serial: Option<yubikey::Serial>,
+    management_key: Option<ManagementKey>,
+    pin_helper: Option<PathBuf>,
+) -> anyhow::Result<Box<dyn Backend>> {
+    match software_keys {
+        Some(path) => {
+            info!("Using software backend, keys are NOT hardware protected");
+            Ok(Box::new(SoftwareBackend::from_file(path)?))
+        }
+        None => Ok(Box::new(YubiKeyBackend::new(
+            serial,
+            management_key,
+            pin_helper,
+        ))),
     }
-    let agreement = piv::decrypt_data_with_transaction(
-        transaction,
-        &their_key[1..],
-        yubikey::piv::AlgorithmId::X25519,
-        key_slot,
-    )
-    .map_err(|err| anyhow!("{err}"))
-    .context("Yubikey failed to calculate agreement")?;
-    Ok(agreement.to_vec())
-}
\ No newline at end of file
+}
@@ -0,0 +1,109 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Identification and authorization of the processes connecting to the socket.
+
+use std::{
+    fmt,
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+
+/// Credentials of the process at the other end of a connection, as reported by the kernel.
+#[derive(Debug)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// Reads the credentials of the peer of `stream` with `SO_PEERCRED`.
+    pub fn of(stream: &UnixStream) -> anyhow::Result<Self> {
+        let mut ucred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `ucred` and `len` are valid for writes and `len` is the size of `ucred`.
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut ucred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to read peer credentials");
+        }
+        Ok(Self {
+            pid: ucred.pid,
+            uid: ucred.uid,
+            gid: ucred.gid,
+        })
+    }
+
+    /// Path of the executable the peer runs, read from `/proc/<pid>/exe`.
+    pub fn executable(&self) -> anyhow::Result<PathBuf> {
+        let link = format!("/proc/{}/exe", self.pid);
+        std::fs::read_link(&link).with_context(|| format!("could not read {link}"))
+    }
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid={} uid={} gid={}", self.pid, self.uid, self.gid)
+    }
+}
+
+/// Which peers may use the server.
+///
+/// A peer is allowed when its uid or gid is allowed and, if any executable is listed, it
+/// runs one of them. Allowed peers whose uid or gid is an admin one may also run
+/// administrative commands.
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub executables: Vec<PathBuf>,
+    pub admin_uids: Vec<u32>,
+    pub admin_gids: Vec<u32>,
+}
+
+impl AccessPolicy {
+    /// Policy allowing only processes running as the same user as the server.
+    pub fn current_user() -> Self {
+        // SAFETY: `getuid` cannot fail and has no preconditions.
+        let uid = unsafe { libc::getuid() };
+        Self {
+            uids: vec![uid],
+            ..Self::default()
+        }
+    }
+
+    /// Checks that `peer` is allowed, returning the reason it is not otherwise.
+    pub fn check(&self, peer: &PeerCredentials) -> anyhow::Result<()> {
+        if !self.uids.contains(&peer.uid) && !self.gids.contains(&peer.gid) {
+            bail!("uid and gid are not allowed");
+        }
+        if self.executables.is_empty() {
+            return Ok(());
+        }
+        let executable = peer.executable()?;
+        if !self.executables.contains(&executable) {
+            bail!("executable {executable:?} is not allowed");
+        }
+        Ok(())
+    }
+
+    /// Whether `peer`, already allowed by [`AccessPolicy::check`], may run administrative
+    /// commands.
+    pub fn is_admin(&self, peer: &PeerCredentials) -> bool {
+        self.admin_uids.contains(&peer.uid) || self.admin_gids.contains(&peer.gid)
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Protocols clients can speak on a connection.
+//!
+//! Connections start with the text protocol and switch to the binary protocol when their
+//! first frame is a binary handshake, see [`binary`].
+
+pub mod binary;
+mod text;
+
+use zeroize::Zeroizing;
+
+use crate::{
+    command::{Notice, Payload, Request},
+    error::ErrorCode,
+};
+
+/// Identifier chosen by the client for a request, echoed in its response so that responses
+/// can be matched to requests when several are in flight.
+pub type RequestId = u32;
+
+/// Protocol spoken on a connection.
+#[derive(Clone, Copy, Debug)]
+pub enum Protocol {
+    Text,
+    Binary { version: u8 },
+}
+
+impl Protocol {
+    /// Decodes the body of a command frame.
+    ///
+    /// The request id is returned even when the rest of the request is invalid, so that the
+    /// error can be matched to it.
+    pub fn decode(self, body: &[u8]) -> (Option<RequestId>, anyhow::Result<Request>) {
+        match self {
+            Protocol::Text => text::decode(body),
+            Protocol::Binary { version } => binary::decode(version, body),
+        }
+    }
+
+    /// Builds the frame of a successful response.
+    pub fn encode_success(self, id: Option<RequestId>, payload: &Payload) -> Zeroizing<Vec<u8>> {
+        match self {
+            Protocol::Text => text::encode_success(id, payload),
+            Protocol::Binary { version } => binary::encode_success(version, id, payload),
+        }
+    }
+
+    /// Whether the client opted in to interim notifications about request `id`: text requests
+    /// carrying an id, and requests of binary version 3 and later. Other clients expect a
+    /// single frame per request.
+    pub fn sends_notices(self, id: Option<RequestId>) -> bool {
+        match self {
+            Protocol::Text => id.is_some(),
+            Protocol::Binary { version } => binary::sends_notices(version),
+        }
+    }
+
+    /// Builds the frame of an interim notification about request `id`, sent before its
+    /// response.
+    pub fn encode_notice(self, id: Option<RequestId>, notice: &Notice) -> Zeroizing<Vec<u8>> {
+        match self {
+            Protocol::Text => text::encode_notice(id, notice),
+            Protocol::Binary { version } => binary::encode_notice(version, id, notice),
+        }
+    }
+
+    /// Builds the frame of an error response.
+    pub fn encode_error(
+        self,
+        id: Option<RequestId>,
+        code: ErrorCode,
+        err: &anyhow::Error,
+    ) -> Zeroizing<Vec<u8>> {
+        match self {
+            Protocol::Text => text::encode_error(id, code, err),
+            Protocol::Binary { version } => binary::encode_error(version, id, code, err),
+        }
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Binary protocol, negotiated with a handshake as the first frame of a connection.
+//!
+//! The handshake is [`MAGIC`] followed by the protocol versions the client supports, one
+//! byte each. The server replies with [`MAGIC`] followed by the highest version both sides
+//! support, or `0` if there is none, in which case the connection is closed.
+//!
+//! In version 1, requests are a command byte followed by fields, each made of a tag byte, a
+//! little-endian `u16` length and the value. Responses are a little-endian `u16` error code,
+//! `0` on success, followed by the raw payload or the UTF-8 error message.
+//!
+//! Version 2 prefixes both requests and responses with a little-endian `u32` request id
+//! chosen by the client, so that several requests can be in flight on a connection.
+//!
+//! Version 3 is version 2 where responses may be preceded by interim notifications, using
+//! code `0xFFFF` and followed by a notification byte and its data.
+
+use anyhow::{anyhow, bail, Context};
+use yubikey::{piv, PinPolicy, Serial, TouchPolicy};
+use zeroize::Zeroizing;
+
+use super::RequestId;
+use crate::{
+    command::{Command, Notice, Payload, Request},
+    error::ErrorCode,
+    frame, key_policy, slot,
+};
+
+/// Prefix of the handshake, which text commands never start with.
+const MAGIC: &[u8] = b"\0SPV";
+
+/// Protocol versions supported by the server.
+const VERSIONS: &[u8] = &[1, 2, 3];
+
+const SUCCESS: u16 = 0;
+
+/// Code of the interim notifications sent before responses, in place of an error code.
+const NOTICE: u16 = 0xFFFF;
+
+/// Notification bytes.
+mod notice_code {
+    /// Followed by the slot id.
+    pub const TOUCH_REQUIRED: u8 = 0x01;
+}
+
+/// Command bytes.
+mod command_code {
+    pub const CLOSE: u8 = 0x01;
+    pub const STATUS: u8 = 0x02;
+    pub const LIST_DEVICES: u8 = 0x03;
+    pub const LIST_SLOTS: u8 = 0x04;
+    pub const CALCULATE_AGREEMENT: u8 = 0x10;
+    pub const SIGN: u8 = 0x11;
+    pub const GET_PUBLIC_KEY: u8 = 0x12;
+    pub const VERIFY_PIN: u8 = 0x13;
+    pub const CHANGE_PIN: u8 = 0x14;
+    pub const CHANGE_PUK: u8 = 0x15;
+    pub const UNBLOCK_PIN: u8 = 0x16;
+    pub const PIN_RETRIES: u8 = 0x17;
+    pub const GENERATE_KEY: u8 = 0x20;
+    pub const IMPORT_KEY: u8 = 0x21;
+    pub const ROTATE_MANAGEMENT_KEY: u8 = 0x22;
+}
+
+/// Field tags.
+mod tag {
+    /// PIV slot id, one byte, e.g. `0x82` for `R1`.
+    pub const SLOT: u8 = 0x01;
+    /// X25519 public key, 32 bytes, optionally prefixed with `0x05`.
+    pub const THEIR_KEY: u8 = 0x02;
+    /// Serial of the device to use, little-endian `u32`.
+    pub const DEVICE: u8 = 0x03;
+    /// Message to sign.
+    pub const MESSAGE: u8 = 0x04;
+    /// PIV PIN policy of a key, one byte.
+    pub const PIN_POLICY: u8 = 0x05;
+    /// PIV touch policy of a key, one byte.
+    pub const TOUCH_POLICY: u8 = 0x06;
+    /// X25519 private key, 32 bytes.
+    pub const PRIVATE_KEY: u8 = 0x07;
+    /// PIN, as ASCII.
+    pub const PIN: u8 = 0x08;
+    /// New PIN, as ASCII.
+    pub const NEW_PIN: u8 = 0x09;
+    /// PUK, as ASCII.
+    pub const PUK: u8 = 0x0A;
+    /// New PUK, as ASCII.
+    pub const NEW_PUK: u8 = 0x0B;
+}
+
+/// Whether `body`, the first frame of a connection, is a binary handshake.
+pub fn is_handshake(body: &[u8]) -> bool {
+    body.starts_with(MAGIC)
+}
+
+/// Returns the highest version supported by both the server and the client `handshake`.
+pub fn negotiate(handshake: &[u8]) -> Option<u8> {
+    handshake[MAGIC.len()..]
+        .iter()
+        .filter(|version| VERSIONS.contains(version))
+        .max()
+        .copied()
+}
+
+/// Builds the reply to the handshake, with the negotiated `version`.
+pub fn encode_handshake(version: Option<u8>) -> Zeroizing<Vec<u8>> {
+    frame::build(MAGIC.len() + 1, |body| {
+        body[..MAGIC.len()].copy_from_slice(MAGIC);
+        body[MAGIC.len()] = version.unwrap_or(0);
+    })
+}
+
+/// Size of the request id prefixing requests and responses from version 2.
+const ID_SIZE: usize = std::mem::size_of::<RequestId>();
+
+pub fn decode(version: u8, body: &[u8]) -> (Option<RequestId>, anyhow::Result<Request>) {
+    match version {
+        1 => (None, decode_request(body)),
+        2 | 3 => match body.split_first_chunk::<ID_SIZE>() {
+            Some((id, body)) => (Some(RequestId::from_le_bytes(*id)), decode_request(body)),
+            None => (None, Err(anyhow!("Missing request id"))),
+        },
+        _ => (
+            None,
+            Err(anyhow!("Unsupported protocol version: {version}")),
+        ),
+    }
+}
+
+fn decode_request(body: &[u8]) -> anyhow::Result<Request> {
+    let (&command_code, fields) = body.split_first().ok_or_else(|| anyhow!("Empty request"))?;
+    let fields = Fields::parse(fields)?;
+
+    let device = fields
+        .get(tag::DEVICE)
+        .map(|serial| -> anyhow::Result<Serial> {
+            let serial: [u8; 4] = serial
+                .try_into()
+                .map_err(|_| anyhow!("Invalid length for device serial"))?;
+            Ok(Serial::from(u32::from_le_bytes(serial)))
+        })
+        .transpose()?;
+
+    let command = match command_code {
+        command_code::CLOSE => Command::Close,
+        command_code::STATUS => Command::Status,
+        command_code::LIST_DEVICES => Command::ListDevices,
+        command_code::LIST_SLOTS => Command::ListSlots,
+        command_code::CALCULATE_AGREEMENT => {
+            parse_calculate_agreement(&fields).context("handling calculate_agreement command")?
+        }
+        command_code::SIGN => parse_sign(&fields).context("handling sign command")?,
+        command_code::GET_PUBLIC_KEY => Command::GetPublicKey {
+            slot: parse_slot(&fields).context("handling get_public_key command")?,
+        },
+        command_code::VERIFY_PIN => Command::VerifyPin {
+            pin: fields
+                .secret(tag::PIN)
+                .context("handling verify_pin command")?,
+        },
+        command_code::CHANGE_PIN => Command::ChangePin {
+            current_pin: fields
+                .secret(tag::PIN)
+                .context("handling change_pin command")?,
+            new_pin: fields
+                .secret(tag::NEW_PIN)
+                .context("handling change_pin command")?,
+        },
+        command_code::CHANGE_PUK => Command::ChangePuk {
+            current_puk: fields
+                .secret(tag::PUK)
+                .context("handling change_puk command")?,
+            new_puk: fields
+                .secret(tag::NEW_PUK)
+                .context("handling change_puk command")?,
+        },
+        command_code::UNBLOCK_PIN => Command::UnblockPin {
+            puk: fields
+                .secret(tag::PUK)
+                .context("handling unblock_pin command")?,
+            new_pin: fields
+                .secret(tag::NEW_PIN)
+                .context("handling unblock_pin command")?,
+        },
+        command_code::PIN_RETRIES => Command::PinRetries,
+        command_code::GENERATE_KEY => {
+            parse_generate_key(&fields).context("handling generate_key command")?
+        }
+        command_code::IMPORT_KEY => {
+            parse_import_key(&fields).context("handling import_key command")?
+        }
+        command_code::ROTATE_MANAGEMENT_KEY => Command::RotateManagementKey,
+        other => {
+            return Err(ErrorCode::UnknownCommand.error(format!("Unknown command: {other:#04x}")))
+        }
+    };
+    Ok(Request { device, command })
+}
+
+fn parse_calculate_agreement(fields: &Fields) -> anyhow::Result<Command> {
+    let slot = parse_slot(fields)?;
+    let their_key = match fields.require(tag::THEIR_KEY)? {
+        [0x05, their_key @ ..] if their_key.len() == 32 => their_key,
+        their_key => their_key,
+    };
+    let their_key = their_key.try_into().map_err(|_| {
+        ErrorCode::BadKey.error(format!(
+            "Invalid length for 'their_key'. Expected '32', got: {}",
+            their_key.len()
+        ))
+    })?;
+    Ok(Command::CalculateAgreement { slot, their_key })
+}
+
+fn parse_sign(fields: &Fields) -> anyhow::Result<Command> {
+    let slot = parse_slot(fields)?;
+    let message = fields.require(tag::MESSAGE)?.to_vec();
+    Ok(Command::Sign { slot, message })
+}
+
+fn parse_generate_key(fields: &Fields) -> anyhow::Result<Command> {
+    let slot = parse_slot(fields)?;
+    let (pin_policy, touch_policy) = parse_key_policies(fields)?;
+    Ok(Command::GenerateKey {
+        slot,
+        pin_policy,
+        touch_policy,
+    })
+}
+
+fn parse_import_key(fields: &Fields) -> anyhow::Result<Command> {
+    let slot = parse_slot(fields)?;
+    let mut private_key = Zeroizing::new([0u8; 32]);
+    match fields.require(tag::PRIVATE_KEY)? {
+        bytes if bytes.len() == 32 => private_key.copy_from_slice(bytes),
+        bytes => {
+            return Err(ErrorCode::BadKey.error(format!(
+                "Invalid length for 'private_key'. Expected '32', got: {}",
+                bytes.len()
+            )))
+        }
+    }
+    let (pin_policy, touch_policy) = parse_key_policies(fields)?;
+    Ok(Command::ImportKey {
+        slot,
+        private_key,
+        pin_policy,
+        touch_policy,
+    })
+}
+
+/// Parses the optional policy fields, missing policies being the device defaults.
+fn parse_key_policies(fields: &Fields) -> anyhow::Result<(PinPolicy, TouchPolicy)> {
+    let pin_policy = match fields.get(tag::PIN_POLICY) {
+        Some([id]) => key_policy::pin_policy_from_id(*id)?,
+        Some(_) => bail!("Invalid length for PIN policy"),
+        None => PinPolicy::Default,
+    };
+    let touch_policy = match fields.get(tag::TOUCH_POLICY) {
+        Some([id]) => key_policy::touch_policy_from_id(*id)?,
+        Some(_) => bail!("Invalid length for touch policy"),
+        None => TouchPolicy::Default,
+    };
+    Ok((pin_policy, touch_policy))
+}
+
+fn parse_slot(fields: &Fields) -> anyhow::Result<piv::SlotId> {
+    match fields.require(tag::SLOT)? {
+        [slot] => slot::from_id(*slot),
+        _ => bail!("Invalid length for slot"),
+    }
+}
+
+/// Fields of a request, by tag.
+struct Fields<'a>(Vec<(u8, &'a [u8])>);
+
+impl<'a> Fields<'a> {
+    fn parse(mut bytes: &'a [u8]) -> anyhow::Result<Self> {
+        let mut fields = Vec::new();
+        while let [tag, len_low, len_high, rest @ ..] = bytes {
+            let len = u16::from_le_bytes([*len_low, *len_high]) as usize;
+            if rest.len() < len {
+                bail!("Field {tag:#04x} is truncated");
+            }
+            if fields.iter().any(|(other, _)| other == tag) {
+                bail!("Field {tag:#04x} is repeated");
+            }
+            fields.push((*tag, &rest[..len]));
+            bytes = &rest[len..];
+        }
+        if !bytes.is_empty() {
+            bail!("Trailing bytes after the last field");
+        }
+        Ok(Self(fields))
+    }
+
+    fn get(&self, tag: u8) -> Option<&'a [u8]> {
+        self.0
+            .iter()
+            .find(|(other, _)| *other == tag)
+            .map(|(_, value)| *value)
+    }
+
+    fn require(&self, tag: u8) -> anyhow::Result<&'a [u8]> {
+        self.get(tag)
+            .ok_or_else(|| anyhow!("Missing field {tag:#04x}"))
+    }
+
+    /// Copies a required field holding a PIN or PUK into a buffer wiped on drop.
+    fn secret(&self, tag: u8) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(self.require(tag)?.to_vec()))
+    }
+}
+
+/// Builds a success frame with the raw payload.
+pub fn encode_success(version: u8, id: Option<RequestId>, payload: &Payload) -> Zeroizing<Vec<u8>> {
+    let payload: &[u8] = match payload {
+        Payload::Secret(secret) => secret,
+        Payload::Bytes(bytes) => bytes,
+        Payload::Text(text) => text.as_bytes(),
+    };
+    encode_response(version, id, SUCCESS, payload)
+}
+
+/// Whether interim notifications are sent to clients speaking `version`.
+pub fn sends_notices(version: u8) -> bool {
+    version >= 3
+}
+
+/// Builds a notice frame: the notification byte followed by its data.
+pub fn encode_notice(version: u8, id: Option<RequestId>, notice: &Notice) -> Zeroizing<Vec<u8>> {
+    let notice = match notice {
+        Notice::TouchRequired(slot) => [notice_code::TOUCH_REQUIRED, u8::from(*slot)],
+    };
+    encode_response(version, id, NOTICE, &notice)
+}
+
+pub fn encode_error(
+    version: u8,
+    id: Option<RequestId>,
+    code: ErrorCode,
+    err: &anyhow::Error,
+) -> Zeroizing<Vec<u8>> {
+    encode_response(version, id, code as u16, format!("{err:#}").as_bytes())
+}
+
+fn encode_response(
+    version: u8,
+    id: Option<RequestId>,
+    code: u16,
+    payload: &[u8],
+) -> Zeroizing<Vec<u8>> {
+    const CODE_SIZE: usize = std::mem::size_of::<u16>();
+    // Errors for requests too short to hold an id are sent with id 0.
+    let id = (version >= 2).then(|| id.unwrap_or(0).to_le_bytes());
+    let id: &[u8] = id.as_ref().map_or(&[], |id| id);
+    frame::build(id.len() + CODE_SIZE + payload.len(), |body| {
+        let (id_buf, body) = body.split_at_mut(id.len());
+        id_buf.copy_from_slice(id);
+        body[..CODE_SIZE].copy_from_slice(&code.to_le_bytes());
+        body[CODE_SIZE..].copy_from_slice(payload);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Message of the error of a request expected to be refused.
+    fn error_message(result: anyhow::Result<impl Sized>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => format!("{err:#}"),
+        }
+    }
+
+    #[test]
+    fn negotiates_highest_common_version() {
+        assert!(is_handshake(b"\0SPV\x01"));
+        assert!(!is_handshake(b"status"));
+        assert_eq!(negotiate(b"\0SPV\x01\x02"), Some(2));
+        assert_eq!(negotiate(b"\0SPV\x03\x01\x09"), Some(3));
+        assert_eq!(negotiate(b"\0SPV\x09"), None);
+        assert_eq!(negotiate(b"\0SPV"), None);
+
+        assert_eq!(
+            encode_handshake(Some(2)).as_slice(),
+            b"\x05\0\0\0\0SPV\x02".as_slice()
+        );
+        assert_eq!(
+            encode_handshake(None).as_slice(),
+            b"\x05\0\0\0\0SPV\x00".as_slice()
+        );
+    }
+
+    #[test]
+    fn parses_fields() {
+        let fields = Fields::parse(&[0x01, 1, 0, 0x82, 0x04, 0, 0]).unwrap();
+        assert_eq!(fields.get(tag::SLOT), Some([0x82].as_slice()));
+        assert_eq!(fields.get(tag::MESSAGE), Some([].as_slice()));
+        assert_eq!(fields.get(tag::PIN), None);
+        assert!(fields.require(tag::PIN).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        assert!(error_message(Fields::parse(&[0x01, 2, 0, 0x82])).contains("truncated"));
+        assert!(
+            error_message(Fields::parse(&[0x01, 1, 0, 0x82, 0x01, 1, 0, 0x83]))
+                .contains("repeated")
+        );
+        assert!(error_message(Fields::parse(&[0x01, 1, 0, 0x82, 0x04, 0])).contains("Trailing"));
+    }
+
+    #[test]
+    fn decodes_request_ids_by_version() {
+        let r1 = slot::parse("R1").unwrap();
+        let get_public_key = [command_code::GET_PUBLIC_KEY, tag::SLOT, 1, 0, 0x82];
+
+        let (id, request) = decode(1, &get_public_key);
+        assert_eq!(id, None);
+        let request = request.unwrap();
+        assert!(matches!(request.command, Command::GetPublicKey { slot } if slot == r1));
+        assert_eq!(request.device, None);
+
+        for version in [2, 3] {
+            let body = [&7u32.to_le_bytes()[..], &get_public_key[..]].concat();
+            let (id, request) = decode(version, &body);
+            assert_eq!(id, Some(7));
+            assert!(matches!(
+                request.unwrap().command,
+                Command::GetPublicKey { slot } if slot == r1
+            ));
+        }
+
+        let (id, request) = decode(2, &[7, 0]);
+        assert_eq!(id, None);
+        assert!(error_message(request).contains("Missing request id"));
+    }
+
+    #[test]
+    fn encodes_responses_by_version() {
+        let payload = Payload::Bytes(vec![0xAA]);
+        assert_eq!(
+            encode_success(1, None, &payload).as_slice(),
+            [3, 0, 0, 0, 0, 0, 0xAA]
+        );
+        assert_eq!(
+            encode_success(2, Some(7), &payload).as_slice(),
+            [7, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0xAA]
+        );
+
+        let code = (ErrorCode::BadKey as u16).to_le_bytes();
+        assert_eq!(
+            encode_error(1, None, ErrorCode::BadKey, &anyhow!("x")).as_slice(),
+            [3, 0, 0, 0, code[0], code[1], b'x']
+        );
+        // Requests too short to hold an id are answered with id 0.
+        assert_eq!(
+            encode_error(2, None, ErrorCode::BadKey, &anyhow!("x")).as_slice(),
+            [7, 0, 0, 0, 0, 0, 0, 0, code[0], code[1], b'x']
+        );
+
+        assert!(!sends_notices(2));
+        assert!(sends_notices(3));
+        let notice = Notice::TouchRequired(slot::parse("R1").unwrap());
+        assert_eq!(
+            encode_notice(3, Some(7), &notice).as_slice(),
+            [
+                8,
+                0,
+                0,
+                0,
+                7,
+                0,
+                0,
+                0,
+                0xFF,
+                0xFF,
+                notice_code::TOUCH_REQUIRED,
+                0x82
+            ]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Text protocol, spoken by connections unless they negotiate another one.
+//!
+//! Commands are space-separated UTF-8 strings, optionally prefixed with `#<id> ` to identify
+//! the request and then `@<serial> ` to select a device. Responses are `success <payload>`,
+//! with secrets hex-encoded, or `error <code> <message>`, with the name of an [`ErrorCode`].
+//! Responses to identified requests are prefixed with the same `#<id> `, and may be preceded
+//! by `notice <notice>` frames, such as `#<id> notice touch_required <slot>`. Clients not
+//! identifying their requests get no notices, which they could not tell from responses.
+
+use anyhow::{anyhow, bail, Context};
+use yubikey::{PinPolicy, Serial, TouchPolicy};
+use zeroize::Zeroizing;
+
+use super::RequestId;
+use crate::{
+    command::{Command, Notice, Payload, Request},
+    error::ErrorCode,
+    frame, key_policy, slot,
+};
+
+pub fn decode(body: &[u8]) -> (Option<RequestId>, anyhow::Result<Request>) {
+    let command = match std::str::from_utf8(body) {
+        Ok(command) => command,
+        Err(_) => {
+            let err = ErrorCode::InvalidRequest.error("Command is not valid UTF-8");
+            return (None, Err(err));
+        }
+    };
+
+    let (id, command) = match command.strip_prefix('#') {
+        Some(command) => {
+            let (id, command) = command.split_once(' ').unwrap_or((command, ""));
+            match id.parse::<RequestId>() {
+                Ok(id) => (Some(id), command),
+                Err(_) => return (None, Err(anyhow!("Invalid request id: {id}"))),
+            }
+        }
+        None => (None, command),
+    };
+    (id, decode_request(command))
+}
+
+fn decode_request(command: &str) -> anyhow::Result<Request> {
+    let (device, command) = match command.strip_prefix('@') {
+        Some(command) => {
+            let (serial, command) = command.split_once(' ').unwrap_or((command, ""));
+            let serial = serial
+                .parse::<u32>()
+                .with_context(|| format!("Invalid device serial: {serial}"))?;
+            (Some(Serial::from(serial)), command)
+        }
+        None => (None, command),
+    };
+
+    let (command_code, command_body) = command.split_once(' ').unwrap_or((command, ""));
+    let command = match command_code {
+        "close" => Command::Close,
+        "status" => Command::Status,
+        "list_devices" => Command::ListDevices,
+        "list_slots" => Command::ListSlots,
+        "calculate_agreement" => parse_calculate_agreement(command_body)
+            .context("handling calculate_agreement command")?,
+        "get_public_key" => Command::GetPublicKey {
+            slot: slot::parse(command_body).context("handling get_public_key command")?,
+        },
+        "sign" => parse_sign(command_body).context("handling sign command")?,
+        "verify_pin" => parse_verify_pin(command_body).context("handling verify_pin command")?,
+        "change_pin" => {
+            let (current_pin, new_pin) = parse_secret_pair(command_body, "current_pin", "new_pin")
+                .context("handling change_pin command")?;
+            Command::ChangePin {
+                current_pin,
+                new_pin,
+            }
+        }
+        "change_puk" => {
+            let (current_puk, new_puk) = parse_secret_pair(command_body, "current_puk", "new_puk")
+                .context("handling change_puk command")?;
+            Command::ChangePuk {
+                current_puk,
+                new_puk,
+            }
+        }
+        "unblock_pin" => {
+            let (puk, new_pin) = parse_secret_pair(command_body, "puk", "new_pin")
+                .context("handling unblock_pin command")?;
+            Command::UnblockPin { puk, new_pin }
+        }
+        "pin_retries" => Command::PinRetries,
+        "rotate_management_key" => Command::RotateManagementKey,
+        "generate_key" => {
+            parse_generate_key(command_body).context("handling generate_key command")?
+        }
+        "import_key" => parse_import_key(command_body).context("handling import_key command")?,
+        _ => {
+            return Err(ErrorCode::UnknownCommand.error(format!("Unknown command: {command_code}")))
+        }
+    };
+    Ok(Request { device, command })
+}
+
+fn parse_calculate_agreement(command_body: &str) -> anyhow::Result<Command> {
+    let (key_slot, command_body) = command_body
+        .split_once(" ")
+        .ok_or(anyhow!("Failed to parse command: missing 'our_key'"))?;
+
+    let (their_key, command_body) = command_body
+        .split_once(" ")
+        .ok_or(anyhow!("Failed to parse command: missing 'their_key'"))?;
+
+    if !command_body.is_empty() {
+        bail!("Failed to parse command, unexpected data at the end of the body: {command_body}")
+    }
+
+    let slot = slot::parse(key_slot)?;
+
+    let their_key = hex::decode(their_key)
+        .map_err(|err| ErrorCode::BadKey.error(format!("Failed to parse 'their_key': {err}")))?;
+    if their_key.len() != 33 {
+        return Err(ErrorCode::BadKey.error(format!(
+            "Invalid length for 'their_key'. Expected '33', got: {}",
+            their_key.len()
+        )));
+    }
+    let their_key = their_key[1..].try_into()?;
+    Ok(Command::CalculateAgreement { slot, their_key })
+}
+
+fn parse_sign(command_body: &str) -> anyhow::Result<Command> {
+    let (key_slot, message) = command_body
+        .split_once(' ')
+        .ok_or(anyhow!("Failed to parse command: missing 'message'"))?;
+
+    let slot = slot::parse(key_slot)?;
+    let message = hex::decode(message).context("Failed to parse 'message'")?;
+    Ok(Command::Sign { slot, message })
+}
+
+fn parse_verify_pin(command_body: &str) -> anyhow::Result<Command> {
+    if command_body.is_empty() || command_body.contains(' ') {
+        bail!("Failed to parse command: expected a single 'pin'");
+    }
+    Ok(Command::VerifyPin {
+        pin: Zeroizing::new(command_body.as_bytes().to_vec()),
+    })
+}
+
+/// Parses two space-separated PINs or PUKs, named `first` and `second` in errors.
+fn parse_secret_pair(
+    command_body: &str,
+    first: &str,
+    second: &str,
+) -> anyhow::Result<(Zeroizing<Vec<u8>>, Zeroizing<Vec<u8>>)> {
+    let mut arguments = command_body
+        .split(' ')
+        .filter(|argument| !argument.is_empty());
+    let first = arguments
+        .next()
+        .ok_or(anyhow!("Failed to parse command: missing '{first}'"))?;
+    let second = arguments
+        .next()
+        .ok_or(anyhow!("Failed to parse command: missing '{second}'"))?;
+    if arguments.next().is_some() {
+        bail!("Failed to parse command, unexpected data at the end of the body");
+    }
+    Ok((
+        Zeroizing::new(first.as_bytes().to_vec()),
+        Zeroizing::new(second.as_bytes().to_vec()),
+    ))
+}
+
+/// Parses `<slot> [<pin policy> [<touch policy>]]`.
+fn parse_generate_key(command_body: &str) -> anyhow::Result<Command> {
+    let mut arguments = command_body.split(' ');
+    let slot = slot::parse(arguments.next().unwrap_or(""))?;
+    let (pin_policy, touch_policy) = parse_key_policies(arguments)?;
+    Ok(Command::GenerateKey {
+        slot,
+        pin_policy,
+        touch_policy,
+    })
+}
+
+/// Parses `<slot> <hex private key> [<pin policy> [<touch policy>]]`.
+fn parse_import_key(command_body: &str) -> anyhow::Result<Command> {
+    let mut arguments = command_body.split(' ');
+    let slot = slot::parse(arguments.next().unwrap_or(""))?;
+    let mut private_key = Zeroizing::new([0u8; 32]);
+    hex::decode_to_slice(arguments.next().unwrap_or(""), private_key.as_mut()).map_err(|_| {
+        ErrorCode::BadKey.error("Failed to parse 'private_key', expected 32 hex-encoded bytes")
+    })?;
+    let (pin_policy, touch_policy) = parse_key_policies(arguments)?;
+    Ok(Command::ImportKey {
+        slot,
+        private_key,
+        pin_policy,
+        touch_policy,
+    })
+}
+
+/// Parses the optional `[<pin policy> [<touch policy>]]` ending a command, missing policies
+/// being the device defaults.
+fn parse_key_policies<'a>(
+    mut arguments: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<(PinPolicy, TouchPolicy)> {
+    let pin_policy = arguments
+        .next()
+        .map(key_policy::parse_pin_policy)
+        .transpose()?
+        .unwrap_or(PinPolicy::Default);
+    let touch_policy = arguments
+        .next()
+        .map(key_policy::parse_touch_policy)
+        .transpose()?
+        .unwrap_or(TouchPolicy::Default);
+    if let Some(unexpected) = arguments.next() {
+        bail!("Failed to parse command, unexpected data at the end of the body: {unexpected}")
+    }
+    Ok((pin_policy, touch_policy))
+}
+
+/// Builds the `success <payload>` frame, hex-encoding secrets in place.
+pub fn encode_success(id: Option<RequestId>, payload: &Payload) -> Zeroizing<Vec<u8>> {
+    let prefix = format!("{}success ", id_prefix(id));
+    match payload {
+        Payload::Secret(secret) => frame::build(prefix.len() + secret.len() * 2, |body| {
+            body[..prefix.len()].copy_from_slice(prefix.as_bytes());
+            hex::encode_to_slice(secret, &mut body[prefix.len()..])
+                .expect("frame is sized for the hex payload");
+        }),
+        Payload::Bytes(bytes) => {
+            frame::encode(format!("{prefix}{}", hex::encode(bytes)).as_bytes())
+        }
+        Payload::Text(text) => frame::encode(format!("{prefix}{text}").as_bytes()),
+    }
+}
+
+/// Builds the `notice <notice>` frame.
+pub fn encode_notice(id: Option<RequestId>, notice: &Notice) -> Zeroizing<Vec<u8>> {
+    let notice = match notice {
+        Notice::TouchRequired(slot) => format!("touch_required {}", slot::name(*slot)),
+    };
+    frame::encode(format!("{}notice {notice}", id_prefix(id)).as_bytes())
+}
+
+/// Builds the `error <code> <message>` frame.
+pub fn encode_error(
+    id: Option<RequestId>,
+    code: ErrorCode,
+    err: &anyhow::Error,
+) -> Zeroizing<Vec<u8>> {
+    frame::encode(format!("{}error {code} {err:#}", id_prefix(id)).as_bytes())
+}
+
+fn id_prefix(id: Option<RequestId>) -> String {
+    match id {
+        Some(id) => format!("#{id} "),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Keeps secret material out of the logs.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+use sha2::{Digest, Sha256};
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Makes [`Secret`] log the length and a fingerprint of the material it wraps.
+///
+/// The fingerprint is a truncated SHA-256, enough to correlate values between client and
+/// server logs without revealing them. Meant for debugging only.
+pub fn enable_debug() {
+    warn!("SECRET DEBUG MODE: lengths and fingerprints of secrets will be logged");
+    DEBUG.store(true, Ordering::Relaxed);
+}
+
+/// Wraps secret material so it can be logged safely.
+///
+/// Displays as `<redacted>`, or with its length and fingerprint once [`enable_debug`] was
+/// called. The material itself is never displayed.
+pub struct Secret<'a>(pub &'a [u8]);
+
+impl fmt::Display for Secret<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !DEBUG.load(Ordering::Relaxed) {
+            return write!(f, "<redacted>");
+        }
+        let digest = Sha256::digest(self.0);
+        write!(
+            f,
+            "<redacted: {} bytes, fingerprint {}>",
+            self.0.len(),
+            hex::encode(&digest[..8])
+        )
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use anyhow::bail;
+use yubikey::piv::{RetiredSlotId, SlotId};
+
+/// Slots usable for key operations, with the name clients refer to them by.
+///
+/// Every slot can also be referred to by its hexadecimal id, e.g. `82` for `R1`.
+pub const SLOTS: [(&str, SlotId); 24] = [
+    ("9A", SlotId::Authentication),
+    ("9C", SlotId::Signature),
+    ("9D", SlotId::KeyManagement),
+    ("9E", SlotId::CardAuthentication),
+    ("R1", SlotId::Retired(RetiredSlotId::R1)),
+    ("R2", SlotId::Retired(RetiredSlotId::R2)),
+    ("R3", SlotId::Retired(RetiredSlotId::R3)),
+    ("R4", SlotId::Retired(RetiredSlotId::R4)),
+    ("R5", SlotId::Retired(RetiredSlotId::R5)),
+    ("R6", SlotId::Retired(RetiredSlotId::R6)),
+    ("R7", SlotId::Retired(RetiredSlotId::R7)),
+    ("R8", SlotId::Retired(RetiredSlotId::R8)),
+    ("R9", SlotId::Retired(RetiredSlotId::R9)),
+    ("R10", SlotId::Retired(RetiredSlotId::R10)),
+    ("R11", SlotId::Retired(RetiredSlotId::R11)),
+    ("R12", SlotId::Retired(RetiredSlotId::R12)),
+    ("R13", SlotId::Retired(RetiredSlotId::R13)),
+    ("R14", SlotId::Retired(RetiredSlotId::R14)),
+    ("R15", SlotId::Retired(RetiredSlotId::R15)),
+    ("R16", SlotId::Retired(RetiredSlotId::R16)),
+    ("R17", SlotId::Retired(RetiredSlotId::R17)),
+    ("R18", SlotId::Retired(RetiredSlotId::R18)),
+    ("R19", SlotId::Retired(RetiredSlotId::R19)),
+    ("R20", SlotId::Retired(RetiredSlotId::R20)),
+];
+
+const VALID_SLOTS: &str = "Valid slots are R1 to R20 (82 to 95), 9A, 9C, 9D and 9E";
+
+/// Parses a key slot as given by clients, either by name (`R1`, `9A`) or by hexadecimal id
+/// (`82`, `0x82`). Matching is case insensitive.
+pub fn parse(slot: &str) -> anyhow::Result<SlotId> {
+    if let Some((_, id)) = SLOTS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(slot))
+    {
+        return Ok(*id);
+    }
+
+    let hex_id = slot
+        .strip_prefix("0x")
+        .or_else(|| slot.strip_prefix("0X"))
+        .unwrap_or(slot);
+    if let Ok(id) = u8::from_str_radix(hex_id, 16) {
+        if let Ok(id) = from_id(id) {
+            return Ok(id);
+        }
+    }
+
+    bail!("Invalid slot id: {slot}. {VALID_SLOTS}")
+}
+
+/// Returns the slot with the given PIV id, e.g. `0x82` for `R1`.
+pub fn from_id(id: u8) -> anyhow::Result<SlotId> {
+    match SLOTS.iter().find(|(_, slot)| u8::from(*slot) == id) {
+        Some((_, slot)) => Ok(*slot),
+        None => bail!("Invalid slot id: {id:02X}. {VALID_SLOTS}"),
+    }
+}
+
+/// Returns the name clients refer to `slot` by.
+pub fn name(slot: SlotId) -> String {
+    match SLOTS.iter().find(|(_, id)| *id == slot) {
+        Some((name, _)) => (*name).to_owned(),
+        None => format!("{:02X}", u8::from(slot)),
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Creation of the Unix Domain Socket the server listens on.
+
+use std::{
+    ffi::CString,
+    fs::Permissions,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use log::info;
+
+const SOCKET_NAME: &str = "signal-piv.sock";
+
+/// Where the socket is created and who can access it.
+#[derive(Debug)]
+pub struct SocketConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. `0o600`.
+    pub mode: u32,
+    /// Gid of the group owning the socket file. The server must be a member of it.
+    pub group: Option<u32>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+            mode: 0o600,
+            group: None,
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/signal-piv.sock`, or `/tmp/signal-piv.sock` when it is not set.
+pub fn default_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => Path::new(&runtime_dir).join(SOCKET_NAME),
+        None => Path::new("/tmp").join(SOCKET_NAME),
+    }
+}
+
+/// Creates the socket described by `config` and listens on it.
+///
+/// A stale socket left at the path by a previous instance is replaced, but anything else
+/// found there, including a socket another instance still serves, is left alone and an
+/// error is returned.
+pub fn bind(config: &SocketConfig) -> anyhow::Result<UnixListener> {
+    let path = &config.path;
+    info!("Starting UDS listener at {path:?}");
+
+    remove_stale_socket(path)?;
+
+    // Restrict the socket from the start so that no connection can be made before its
+    // permissions are set. Startup is still single threaded, so changing the umask is safe.
+    // SAFETY: `umask` cannot fail and has no preconditions.
+    let previous_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    // SAFETY: as above.
+    unsafe { libc::umask(previous_umask) };
+    let listener = listener.context("Could not create the unix socket")?;
+
+    if let Some(gid) = config.group {
+        std::os::unix::fs::chown(path, None, Some(gid))
+            .with_context(|| format!("could not change group of {path:?} to {gid}"))?;
+    }
+    std::fs::set_permissions(path, Permissions::from_mode(config.mode))
+        .with_context(|| format!("could not set permissions of {path:?}"))?;
+
+    Ok(listener)
+}
+
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("could not inspect {path:?}")),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{path:?} exists and is not a socket, refusing to remove it");
+    }
+    // Only a refused connection shows that nothing serves the socket: other failures, such as
+    // a denied permission, say nothing about it.
+    match UnixStream::connect(path) {
+        Ok(_) => bail!("{path:?} is served by another running instance"),
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("could not check whether {path:?} is in use"))
+        }
+    }
+
+    info!("A stale socket is present. Deleting...");
+    std::fs::remove_file(path)
+        .with_context(|| format!("could not delete previous socket at {path:?}"))
+}
+
+/// Returns the gid of `group`, given by name or gid.
+pub fn resolve_group(group: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).context("Invalid group name")?;
+    // SAFETY: `name` is a valid C string. The returned entry is only read before any other
+    // call that could overwrite it.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("Unknown group: {group}");
+    }
+    // SAFETY: `entry` is not null and points to a valid `group` structure.
+    Ok(unsafe { (*entry).gr_gid })
+}
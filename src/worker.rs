@@ -0,0 +1,93 @@
+// Copyright (c) SandboxAQ. All rights reserved.
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context};
+use log::{error, info};
+
+use crate::{
+    backend::Backend,
+    command::{self, Notice, Payload, Policy, Request},
+    error::ErrorCode,
+};
+
+/// Number of requests that can wait for the worker before new ones are rejected.
+const QUEUE_CAPACITY: usize = 32;
+
+/// Called with the interim notifications of a request while it is executed.
+type Notify = Box<dyn Fn(Notice) + Send>;
+
+/// Called with the result of a request once it was executed.
+type Reply = Box<dyn FnOnce(anyhow::Result<Payload>) + Send>;
+
+struct Job {
+    request: Request,
+    notify: Notify,
+    reply: Reply,
+}
+
+/// Handle on the thread that owns the backend.
+///
+/// Requests from every connection are queued and executed one at a time, in the order they
+/// were submitted, so a single device transaction is never shared between clients.
+#[derive(Clone)]
+pub struct Worker {
+    jobs: SyncSender<Job>,
+}
+
+impl Worker {
+    pub fn spawn(backend: Box<dyn Backend>, policy: Policy) -> anyhow::Result<Self> {
+        let (jobs, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("backend-worker".to_owned())
+            .spawn(move || run(backend, policy, receiver))
+            .context("Failed to spawn the backend worker")?;
+        Ok(Self { jobs })
+    }
+
+    /// Queues `request`, `notify` being called with its interim notifications and `reply`
+    /// with its result from the worker thread.
+    ///
+    /// Fails immediately when the queue is full rather than blocking the caller, in which
+    /// case neither is called.
+    pub fn submit(
+        &self,
+        request: Request,
+        notify: impl Fn(Notice) + Send + 'static,
+        reply: impl FnOnce(anyhow::Result<Payload>) + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let job = Job {
+            request,
+            notify: Box::new(notify),
+            reply: Box::new(reply),
+        };
+        match self.jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                Err(ErrorCode::Busy.error("Server busy, try again later"))
+            }
+            Err(TrySendError::Disconnected(_)) => bail!("Backend worker stopped"),
+        }
+    }
+}
+
+fn run(mut backend: Box<dyn Backend>, policy: Policy, jobs: Receiver<Job>) {
+    info!("Backend worker started");
+    for job in jobs {
+        // A bug triggered by one request must not take the worker, and every client with it,
+        // down.
+        let response = panic::catch_unwind(AssertUnwindSafe(|| {
+            command::execute(backend.as_mut(), &policy, &job.request, &job.notify)
+        }))
+        .unwrap_or_else(|_| {
+            error!("Command handler panicked");
+            Err(anyhow!("Internal error"))
+        });
+        (job.reply)(response);
+    }
+}